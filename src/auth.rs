@@ -0,0 +1,289 @@
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+
+use crate::{ClientRateLimitConfig, ConfigFile, RequestParams};
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig{
+	/** 受け付けるAPIキー*/
+	#[serde(default)]
+	keys:Vec<ApiKeyConfig>,
+	/** 署名付きURLの検証に使う共有鍵。nullで署名付きURLを受け付けない*/
+	#[serde(default)]
+	hmac_secret:Option<String>,
+}
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig{
+	/** ログや流量制限で使う名前*/
+	pub name:String,
+	key:String,
+	/** このキー専用の流量制限。nullの場合はIPアドレス毎の制限を使う*/
+	#[serde(default)]
+	pub rate_limit:Option<ClientRateLimitConfig>,
+}
+pub enum Authorized<'a>{
+	/** 認証が無効*/
+	Anonymous,
+	Key(&'a ApiKeyConfig),
+	/** 署名付きURL*/
+	Signed,
+}
+/** リクエストがAPIキーか署名を持っているか確認する。Errは拒否した理由*/
+pub fn authorize<'a>(config:&'a ConfigFile,headers:&axum::http::HeaderMap,q:&RequestParams)->Result<Authorized<'a>,&'static str>{
+	let auth=match &config.auth{
+		Some(auth)=>auth,
+		None=>return Ok(Authorized::Anonymous),
+	};
+	if let Some(res)=authorize_key(auth,headers,q.key.as_deref()){
+		return res;
+	}
+	if let (Some(expires),Some(signature))=(q.expires,q.signature.as_ref()){
+		let secret=auth.hmac_secret.as_ref().ok_or("signed url disabled")?;
+		if expires<chrono::Utc::now().timestamp(){
+			return Err("signature expired");
+		}
+		let signature=decode_hex(signature).ok_or("invalid signature")?;
+		let mut mac=hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).map_err(|_|"invalid signature")?;
+		mac.update(signed_message(q).as_bytes());
+		return match mac.verify_slice(&signature){
+			Ok(_)=>Ok(Authorized::Signed),
+			Err(_)=>Err("invalid signature"),
+		};
+	}
+	Err("unauthorized")
+}
+/** /batchのようにAPIキーでのみ受け付けるリクエストを確認する。署名付きURLは1件ずつにしか使えない*/
+pub fn authorize_batch<'a>(config:&'a ConfigFile,headers:&axum::http::HeaderMap,key:Option<&str>)->Result<Authorized<'a>,&'static str>{
+	let auth=match &config.auth{
+		Some(auth)=>auth,
+		None=>return Ok(Authorized::Anonymous),
+	};
+	authorize_key(auth,headers,key).unwrap_or(Err("unauthorized"))
+}
+/** AuthorizationヘッダかkeyでAPIキーが指定されていれば確認する。Authorizationヘッダを優先する*/
+fn authorize_key<'a>(auth:&'a AuthConfig,headers:&axum::http::HeaderMap,key:Option<&str>)->Option<Result<Authorized<'a>,&'static str>>{
+	let bearer=headers.get(axum::http::header::AUTHORIZATION).and_then(|v|v.to_str().ok()).and_then(|v|{
+		let (scheme,token)=v.split_once(' ')?;
+		scheme.eq_ignore_ascii_case("bearer").then(||token.trim())
+	});
+	let key=bearer.or(key)?;
+	Some(match auth.keys.iter().find(|k|constant_time_eq(k.key.as_bytes(),key.as_bytes())){
+		Some(k)=>Ok(Authorized::Key(k)),
+		None=>Err("invalid api key"),
+	})
+}
+/**
+ * 署名の対象。keyとsignature以外の指定されたパラメータを名前順に並べ、
+ * 値をパーセントエンコード(RFC 3986の非予約文字以外)して`name=value`を`&`で繋いだもの。
+ * 例: `expires=1700000000&lang=ja&url=https%3A%2F%2Fexample.com%2F`
+ * HMAC-SHA256(hmac_secret,署名の対象)を16進数でsignatureに指定する
+ */
+fn signed_message(q:&RequestParams)->String{
+	//名前順
+	let params=[
+		("contentLengthLimit",q.content_length_limit.map(|v|v.to_string())),
+		("debug",q.debug.map(|v|v.to_string())),
+		("expires",q.expires.map(|v|v.to_string())),
+		("lang",q.lang.clone()),
+		("responseTimeout",q.response_timeout.map(|v|v.to_string())),
+		("url",Some(q.url.clone())),
+		("userAgent",q.user_agent.clone()),
+	];
+	params.iter().filter_map(|(name,value)|Some(format!("{}={}",name,urlencoding::encode(value.as_ref()?)))).collect::<Vec<_>>().join("&")
+}
+fn decode_hex(s:&str)->Option<Vec<u8>>{
+	if !s.len().is_multiple_of(2){
+		return None;
+	}
+	(0..s.len()).step_by(2).map(|i|u8::from_str_radix(s.get(i..i+2)?,16).ok()).collect()
+}
+/** キーの一致を比較時間から推測されないようにする*/
+fn constant_time_eq(a:&[u8],b:&[u8])->bool{
+	if a.len()!=b.len(){
+		return false;
+	}
+	a.iter().zip(b.iter()).fold(0,|acc,(a,b)|acc|(a^b))==0
+}
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	const URL:&str="https://example.com/";
+	fn config()->ConfigFile{
+		ConfigFile{
+			auth:Some(AuthConfig{
+				keys:vec![ApiKeyConfig{
+					name:"test".to_owned(),
+					key:"secret-key".to_owned(),
+					rate_limit:None,
+				}],
+				hmac_secret:Some("hmac-secret".to_owned()),
+			}),
+			..Default::default()
+		}
+	}
+	fn params(key:Option<&str>,expires:Option<i64>,signature:Option<String>)->RequestParams{
+		RequestParams{
+			url:URL.to_owned(),
+			lang:None,
+			user_agent:None,
+			response_timeout:None,
+			content_length_limit:None,
+			key:key.map(|s|s.to_owned()),
+			expires,
+			signature,
+			debug:None,
+		}
+	}
+	fn sign(secret:&str,q:&RequestParams)->String{
+		let mut mac=hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+		mac.update(signed_message(q).as_bytes());
+		mac.finalize().into_bytes().iter().map(|b|format!("{:02x}",b)).collect()
+	}
+	/** expiresまで指定したパラメータに署名する*/
+	fn signed(secret:&str,mut q:RequestParams)->RequestParams{
+		q.signature=Some(sign(secret,&q));
+		q
+	}
+	fn bearer(token:&str)->axum::http::HeaderMap{
+		let mut headers=axum::http::HeaderMap::new();
+		headers.insert(axum::http::header::AUTHORIZATION,format!("Bearer {}",token).parse().unwrap());
+		headers
+	}
+	#[test]
+	fn anonymous_without_auth(){
+		let config=ConfigFile::default();
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&params(None,None,None));
+		assert!(matches!(res,Ok(Authorized::Anonymous)));
+	}
+	#[test]
+	fn missing_credentials(){
+		let config=config();
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&params(None,None,None));
+		assert!(matches!(res,Err("unauthorized")));
+	}
+	#[test]
+	fn bearer_key(){
+		let config=config();
+		let res=authorize(&config,&bearer("secret-key"),&params(None,None,None));
+		assert!(matches!(res,Ok(Authorized::Key(k)) if k.name=="test"));
+		let res=authorize(&config,&bearer("wrong-key"),&params(None,None,None));
+		assert!(matches!(res,Err("invalid api key")));
+	}
+	#[test]
+	fn query_key(){
+		let config=config();
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&params(Some("secret-key"),None,None));
+		assert!(matches!(res,Ok(Authorized::Key(k)) if k.name=="test"));
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&params(Some("wrong-key"),None,None));
+		assert!(matches!(res,Err("invalid api key")));
+	}
+	#[test]
+	fn bearer_takes_precedence_over_query_key(){
+		let config=config();
+		let res=authorize(&config,&bearer("wrong-key"),&params(Some("secret-key"),None,None));
+		assert!(matches!(res,Err("invalid api key")));
+		let res=authorize(&config,&bearer("secret-key"),&params(Some("wrong-key"),None,None));
+		assert!(matches!(res,Ok(Authorized::Key(_))));
+	}
+	#[test]
+	fn valid_signature(){
+		let config=config();
+		let expires=chrono::Utc::now().timestamp()+60;
+		let mut q=signed("hmac-secret",params(None,Some(expires),None));
+		q.signature=q.signature.map(|s|s.to_ascii_uppercase());
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&q);
+		assert!(matches!(res,Ok(Authorized::Signed)));
+	}
+	#[test]
+	fn canonical_message(){
+		let mut q=params(Some("secret-key"),Some(1700000000),Some("00".to_owned()));
+		q.url="https://example.com/?a=1&b=2".to_owned();
+		q.lang=Some("ja-JP".to_owned());
+		q.user_agent=Some("Mozilla/5.0 (X11)".to_owned());
+		q.response_timeout=Some(3000);
+		assert_eq!(
+			signed_message(&q),
+			"expires=1700000000&lang=ja-JP&responseTimeout=3000&url=https%3A%2F%2Fexample.com%2F%3Fa%3D1%26b%3D2&userAgent=Mozilla%2F5.0%20%28X11%29"
+		);
+	}
+	#[test]
+	fn expired_signature(){
+		let config=config();
+		let expires=chrono::Utc::now().timestamp()-60;
+		let q=signed("hmac-secret",params(None,Some(expires),None));
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&q);
+		assert!(matches!(res,Err("signature expired")));
+	}
+	#[test]
+	fn forged_signature(){
+		let config=config();
+		let expires=chrono::Utc::now().timestamp()+60;
+		//別の鍵で署名した
+		let q=signed("other-secret",params(None,Some(expires),None));
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&q);
+		assert!(matches!(res,Err("invalid signature")));
+		//別のURLの署名を流用した
+		let mut q=signed("hmac-secret",params(None,Some(expires),None));
+		q.url="https://example.net/".to_owned();
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&q);
+		assert!(matches!(res,Err("invalid signature")));
+		//有効期限を書き換えた
+		let mut q=signed("hmac-secret",params(None,Some(expires),None));
+		q.expires=Some(expires+3600);
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&q);
+		assert!(matches!(res,Err("invalid signature")));
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&params(None,Some(expires),Some("zz".to_owned())));
+		assert!(matches!(res,Err("invalid signature")));
+	}
+	#[test]
+	fn tampered_parameters(){
+		let config=config();
+		let expires=chrono::Utc::now().timestamp()+60;
+		let mut q=params(None,Some(expires),None);
+		q.lang=Some("ja".to_owned());
+		let q=signed("hmac-secret",q);
+		assert!(matches!(authorize(&config,&axum::http::HeaderMap::new(),&q),Ok(Authorized::Signed)));
+		//署名後に追加や変更したパラメータも拒否する
+		let mut tampered=signed("hmac-secret",params(None,Some(expires),None));
+		tampered.lang=q.lang.clone();
+		assert!(matches!(authorize(&config,&axum::http::HeaderMap::new(),&tampered),Err("invalid signature")));
+		let mut tampered=params(None,Some(expires),q.signature.clone());
+		tampered.lang=Some("en".to_owned());
+		assert!(matches!(authorize(&config,&axum::http::HeaderMap::new(),&tampered),Err("invalid signature")));
+		let mut tampered=params(None,Some(expires),q.signature.clone());
+		tampered.lang=q.lang.clone();
+		tampered.user_agent=Some("bot".to_owned());
+		assert!(matches!(authorize(&config,&axum::http::HeaderMap::new(),&tampered),Err("invalid signature")));
+		let mut tampered=params(None,Some(expires),q.signature.clone());
+		tampered.lang=q.lang.clone();
+		tampered.response_timeout=Some(60000);
+		assert!(matches!(authorize(&config,&axum::http::HeaderMap::new(),&tampered),Err("invalid signature")));
+	}
+	#[test]
+	fn signature_disabled(){
+		let mut config=config();
+		config.auth.as_mut().unwrap().hmac_secret=None;
+		let expires=chrono::Utc::now().timestamp()+60;
+		let q=signed("hmac-secret",params(None,Some(expires),None));
+		let res=authorize(&config,&axum::http::HeaderMap::new(),&q);
+		assert!(matches!(res,Err("signed url disabled")));
+	}
+	#[test]
+	fn batch_requires_key(){
+		let config=config();
+		let res=authorize_batch(&config,&axum::http::HeaderMap::new(),None);
+		assert!(matches!(res,Err("unauthorized")));
+		let res=authorize_batch(&config,&axum::http::HeaderMap::new(),Some("wrong-key"));
+		assert!(matches!(res,Err("invalid api key")));
+		let res=authorize_batch(&config,&axum::http::HeaderMap::new(),Some("secret-key"));
+		assert!(matches!(res,Ok(Authorized::Key(_))));
+		let res=authorize_batch(&config,&bearer("secret-key"),None);
+		assert!(matches!(res,Ok(Authorized::Key(_))));
+		let config=ConfigFile::default();
+		let res=authorize_batch(&config,&axum::http::HeaderMap::new(),None);
+		assert!(matches!(res,Ok(Authorized::Anonymous)));
+	}
+}
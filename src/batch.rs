@@ -0,0 +1,104 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::response::IntoResponse;
+use futures::StreamExt;
+use serde::Deserialize;
+
+use crate::{ConfigFile, RateLimit, RequestParams};
+
+#[derive(Debug, Deserialize)]
+pub struct BatchParams{
+	/** ndjsonを指定すると終わったものから1行ずつ返す*/
+	stream:Option<String>,
+	/** Authorizationヘッダを使えない場合のAPIキー。キーも署名も無い要素にも使う*/
+	key:Option<String>,
+}
+/** 複数のURLをまとめて要約する。結果はリクエストと同じ順番で返す*/
+pub async fn post_batch(
+	connect_info:axum::extract::ConnectInfo<SocketAddr>,
+	request_headers:axum::http::HeaderMap,
+	(client,config,limit):(reqwest::Client,Arc<ConfigFile>,RateLimit),
+	axum::extract::Query(bq):axum::extract::Query<BatchParams>,
+	body:axum::body::Bytes,
+)->axum::response::Response{
+	//中身を読む前に拒否する
+	if let Err(e)=crate::auth::authorize_batch(&config,&request_headers,bq.key.as_deref()){
+		let mut headers=axum::http::HeaderMap::new();
+		headers.append("X-Proxy-Error",e.parse().unwrap());
+		return (axum::http::StatusCode::UNAUTHORIZED,headers).into_response();
+	}
+	let mut items:Vec<RequestParams>=match serde_json::from_slice(&body){
+		Ok(items)=>items,
+		Err(e)=>{
+			let mut headers=axum::http::HeaderMap::new();
+			headers.append("X-Proxy-Error",format!("invalid batch:line {} column {}",e.line(),e.column()).parse().unwrap());
+			return (axum::http::StatusCode::BAD_REQUEST,headers).into_response();
+		}
+	};
+	if items.len()>config.batch_max_size{
+		let mut headers=axum::http::HeaderMap::new();
+		headers.append("X-Proxy-Error",format!("batch size:{}>{}",items.len(),config.batch_max_size).parse().unwrap());
+		return (axum::http::StatusCode::PAYLOAD_TOO_LARGE,headers).into_response();
+	}
+	for q in items.iter_mut(){
+		if q.key.is_none()&&q.signature.is_none(){
+			q.key=bq.key.clone();
+		}
+	}
+	//全ての要素で共有する締め切り
+	let deadline=tokio::time::Instant::now()+std::time::Duration::from_millis(config.batch_timeout);
+	let len=items.len();
+	let mut headers=axum::http::HeaderMap::new();
+	let tasks=items.into_iter().enumerate().map(move|(index,q)|{
+		let args=(client.clone(),config.clone(),limit.clone());
+		let request_headers=request_headers.clone();
+		async move{
+			let res=tokio::time::timeout_at(deadline,crate::get_file(None,connect_info,request_headers,args,axum::extract::Query(q))).await;
+			(index,batch_item(res).await)
+		}
+	});
+	if bq.stream.as_deref()==Some("ndjson"){
+		headers.append(axum::http::header::CONTENT_TYPE,"application/x-ndjson".parse().unwrap());
+		let stream=futures::stream::iter(tasks).buffer_unordered(len.max(1)).map(|(index,mut item)|{
+			item["index"]=index.into();
+			Ok::<_,std::convert::Infallible>(format!("{}\n",item))
+		});
+		(axum::http::StatusCode::OK,headers,axum::body::Body::from_stream(stream)).into_response()
+	}else{
+		headers.append(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+		let results:Vec<serde_json::Value>=futures::future::join_all(tasks).await.into_iter().map(|(_,item)|item).collect();
+		(axum::http::StatusCode::OK,headers,serde_json::Value::Array(results).to_string()).into_response()
+	}
+}
+/** 単体のリクエストの応答を{"status":200,"result":{..}}か{"status":502,"error":".."}にする*/
+async fn batch_item(res:Result<axum::response::Response,tokio::time::error::Elapsed>)->serde_json::Value{
+	let res=match res{
+		Ok(res)=>res,
+		Err(_)=>{
+			return serde_json::json!({
+				"status":axum::http::StatusCode::GATEWAY_TIMEOUT.as_u16(),
+				"error":"batch deadline exceeded",
+			});
+		}
+	};
+	let status=res.status();
+	if status.is_success(){
+		if let Ok(body)=axum::body::to_bytes(res.into_body(),usize::MAX).await{
+			if let Ok(result)=serde_json::from_slice::<serde_json::Value>(&body){
+				return serde_json::json!({
+					"status":status.as_u16(),
+					"result":result,
+				});
+			}
+		}
+		return serde_json::json!({
+			"status":axum::http::StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+			"error":"invalid result",
+		});
+	}
+	let error=res.headers().get("X-Proxy-Error").and_then(|v|v.to_str().ok()).map(|v|v.to_owned());
+	serde_json::json!({
+		"status":status.as_u16(),
+		"error":error.unwrap_or_else(||status.canonical_reason().unwrap_or_default().to_owned()),
+	})
+}
@@ -0,0 +1,93 @@
+use std::sync::LazyLock;
+
+use publicsuffix::Psl;
+use serde::{Deserialize, Serialize};
+
+/** ページが主張するcanonical URLの扱い*/
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum CanonicalPolicy{
+	/** 取得したURLと同じ登録可能ドメインの場合のみurlを置き換える*/
+	SameSite,
+	/** urlは取得したURLのままにしてcanonicalUrlで返す*/
+	Separate,
+}
+/** https://publicsuffix.org/list/public_suffix_list.dat の写し。github.io等の共有ホスティングも含む*/
+static PUBLIC_SUFFIX_LIST:LazyLock<publicsuffix::List>=LazyLock::new(||{
+	include_str!("public_suffix_list.dat").parse().expect("invalid public suffix list")
+});
+/** 公開接尾辞の1つ下のドメイン。IPアドレスや公開接尾辞そのものはホスト全体*/
+fn registrable_domain(host:&str)->String{
+	let host=host.trim_end_matches('.').to_ascii_lowercase();
+	if host.parse::<std::net::Ipv4Addr>().is_ok()||host.starts_with('['){
+		return host;
+	}
+	match PUBLIC_SUFFIX_LIST.domain(host.as_bytes()).and_then(|d|std::str::from_utf8(d.as_bytes()).ok()){
+		Some(domain)=>domain.to_owned(),
+		None=>host,
+	}
+}
+/** 取得したページを基準に解決する。http(s)以外は使わない*/
+pub fn resolve(url:&str,base_url:&reqwest::Url)->Result<reqwest::Url,String>{
+	let url=base_url.join(url.trim()).map_err(|e|format!("invalid url: {}",e))?;
+	match url.scheme(){
+		"http"|"https"=>Ok(url),
+		scheme=>Err(format!("scheme {} is not allowed",scheme)),
+	}
+}
+pub fn same_site(a:&reqwest::Url,b:&reqwest::Url)->bool{
+	match (a.host_str(),b.host_str()){
+		(Some(a),Some(b))=>registrable_domain(a)==registrable_domain(b),
+		_=>false,
+	}
+}
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn url(s:&str)->reqwest::Url{
+		reqwest::Url::parse(s).unwrap()
+	}
+	#[test]
+	fn registrable_domains(){
+		assert_eq!(registrable_domain("www.example.com"),"example.com");
+		assert_eq!(registrable_domain("a.b.example.co.jp"),"example.co.jp");
+		assert_eq!(registrable_domain("news.bbc.co.uk"),"bbc.co.uk");
+		assert_eq!(registrable_domain("Example.COM."),"example.com");
+		assert_eq!(registrable_domain("alice.github.io"),"alice.github.io");
+		assert_eq!(registrable_domain("127.0.0.1"),"127.0.0.1");
+		assert_eq!(registrable_domain("[::1]"),"[::1]");
+		//公開接尾辞そのもの
+		assert_eq!(registrable_domain("github.io"),"github.io");
+		assert_eq!(registrable_domain("localhost"),"localhost");
+	}
+	#[test]
+	fn same_site_subdomains(){
+		assert!(same_site(&url("https://example.com/a"),&url("https://www.example.com/b")));
+		assert!(same_site(&url("https://m.example.co.jp/"),&url("http://example.co.jp/")));
+		assert!(!same_site(&url("https://example.com/"),&url("https://example.net/")));
+		assert!(!same_site(&url("https://a.example.co.jp/"),&url("https://b.co.jp/")));
+	}
+	#[test]
+	fn shared_hosting_is_not_same_site(){
+		for suffix in ["github.io","blogspot.com","herokuapp.com","pages.dev","vercel.app"]{
+			let a=url(&format!("https://alice.{}/",suffix));
+			let b=url(&format!("https://mallory.{}/",suffix));
+			assert!(!same_site(&a,&b),"{}",suffix);
+			assert!(same_site(&a,&url(&format!("https://www.alice.{}/",suffix))),"{}",suffix);
+		}
+	}
+	#[test]
+	fn ip_addresses(){
+		assert!(same_site(&url("http://192.0.2.1/a"),&url("http://192.0.2.1/b")));
+		assert!(!same_site(&url("http://192.0.2.1/"),&url("http://192.0.2.2/")));
+		assert!(!same_site(&url("http://[2001:db8::1]/"),&url("http://[2001:db8::2]/")));
+	}
+	#[test]
+	fn resolve_schemes(){
+		let base=url("https://example.com/a/b");
+		assert_eq!(resolve("../c",&base).unwrap().as_str(),"https://example.com/c");
+		assert!(resolve("javascript:alert(1)",&base).is_err());
+		assert!(resolve("data:text/html,x",&base).is_err());
+	}
+}
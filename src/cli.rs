@@ -0,0 +1,88 @@
+use crate::{ConfigFile, RequestParams};
+
+const USAGE:&str="usage: summaly-rs fetch <url> [--lang <lang>] [--user-agent <ua>] [--pretty] [--explain]
+       summaly-rs fetch --from-file <page.html> --base-url <url> [--pretty] [--explain]";
+
+/** fetch サーバーを起動せずに要約を標準出力に書き出して終了コードを返す*/
+pub fn fetch(args:&[String],config:ConfigFile)->i32{
+	let mut url=None;
+	let mut lang=None;
+	let mut user_agent=None;
+	let mut from_file=None;
+	let mut base_url=None;
+	let mut pretty=false;
+	let mut explain=false;
+	let mut args=args.iter();
+	while let Some(arg)=args.next(){
+		match arg.as_str(){
+			"--lang"=>lang=args.next().cloned(),
+			"--user-agent"=>user_agent=args.next().cloned(),
+			"--from-file"=>from_file=args.next().cloned(),
+			"--base-url"=>base_url=args.next().cloned(),
+			"--pretty"=>pretty=true,
+			"--explain"=>explain=true,
+			s if !s.starts_with("--")&&url.is_none()=>url=Some(s.to_owned()),
+			_=>{
+				eprintln!("{}",USAGE);
+				return 2;
+			}
+		}
+	}
+	let url=match (url,&from_file,base_url){
+		(Some(url),None,None)=>url,
+		(None,Some(_),Some(base_url))=>base_url,
+		_=>{
+			eprintln!("{}",USAGE);
+			return 2;
+		}
+	};
+	let q=RequestParams{
+		url,
+		lang,
+		user_agent,
+		response_timeout:None,
+		content_length_limit:None,
+		key:None,
+		expires:None,
+		signature:None,
+		debug:Some(explain as u8),
+	};
+	let config=std::sync::Arc::new(config);
+	let client=crate::http_client(&config);
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let result=rt.block_on(async{
+		match &from_file{
+			Some(path)=>{
+				let html=std::fs::read(path).map_err(|e|format!("{}: {}",path,e))?;
+				let deadline=tokio::time::Instant::now()+std::time::Duration::from_millis(config.timeout);
+				let sub=crate::subrequest::Subrequest::new(&client,None,None,&config,&q,deadline);
+				let resp=crate::summarize(&html,&q,&sub,crate::explain::Explain::new(explain)).await?;
+				serde_json::to_value(resp).map_err(|e|e.to_string())
+			},
+			None=>{
+				let res=crate::remote_request(axum::http::HeaderMap::new(),(client.clone(),config.clone()),None,q).await;
+				let status=res.status();
+				if !status.is_success(){
+					let error=res.headers().get("X-Proxy-Error").and_then(|v|v.to_str().ok()).unwrap_or_default().to_owned();
+					return Err(format!("{} {}",status,error));
+				}
+				let body=axum::body::to_bytes(res.into_body(),usize::MAX).await.map_err(|e|e.to_string())?;
+				serde_json::from_slice(&body).map_err(|e|e.to_string())
+			},
+		}
+	});
+	match result{
+		Ok(v)=>{
+			if pretty{
+				println!("{}",serde_json::to_string_pretty(&v).unwrap());
+			}else{
+				println!("{}",v);
+			}
+			0
+		},
+		Err(e)=>{
+			println!("error: {}",e);
+			1
+		}
+	}
+}
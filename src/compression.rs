@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use tower_http::compression::{predicate::{NotForContentType, Predicate, SizeAbove}, CompressionLayer, CompressionLevel};
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig{
+	#[serde(default="default_true")]
+	pub gzip:bool,
+	#[serde(default="default_true")]
+	pub br:bool,
+	#[serde(default="default_true")]
+	pub zstd:bool,
+	/** これより小さいレスポンスは圧縮しない(bytes)*/
+	#[serde(default="default_min_size")]
+	pub min_size:u64,
+	/** "fastest"、"best"、"default"または各アルゴリズムの圧縮レベルの数値*/
+	#[serde(default="default_level")]
+	pub level:String,
+}
+fn default_true()->bool{
+	true
+}
+fn default_min_size()->u64{
+	256
+}
+fn default_level()->String{
+	"default".to_owned()
+}
+impl Default for CompressionConfig{
+	fn default()->Self{
+		Self{
+			gzip:true,
+			br:true,
+			zstd:true,
+			min_size:default_min_size(),
+			level:default_level(),
+		}
+	}
+}
+impl CompressionConfig{
+	fn level(&self)->Result<CompressionLevel,String>{
+		match self.level.as_str(){
+			"fastest"=>Ok(CompressionLevel::Fastest),
+			"best"=>Ok(CompressionLevel::Best),
+			"default"=>Ok(CompressionLevel::Default),
+			s=>s.parse().map(CompressionLevel::Precise).map_err(|_|format!("compression.level: expected \"fastest\", \"best\", \"default\" or an integer, got {:?}",s)),
+		}
+	}
+	pub fn validate(&self)->Vec<String>{
+		self.level().err().into_iter().collect()
+	}
+	/** Accept-Encodingに応じてgzip/br/zstdから選んで圧縮する*/
+	pub fn layer(&self)->CompressionLayer<impl Predicate>{
+		let predicate=SizeAbove::new(self.min_size)
+			.and(NotForContentType::GRPC)
+			.and(NotForContentType::IMAGES)
+			.and(NotForContentType::SSE)
+			//POST /batch?stream=ndjsonは1行ずつ届くようにする
+			.and(NotForContentType::const_new("application/x-ndjson"));
+		CompressionLayer::new()
+			.gzip(self.gzip)
+			.br(self.br)
+			.zstd(self.zstd)
+			.quality(self.level().unwrap_or_default())
+			.compress_when(predicate)
+	}
+}
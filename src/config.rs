@@ -0,0 +1,356 @@
+use std::io::Write;
+
+use crate::ConfigFile;
+
+/** 環境変数で設定を上書きする時の接頭辞。入れ子のキーは__で区切る(例: SUMMALY_LOG__FORMAT=json)*/
+const ENV_PREFIX:&str="SUMMALY_";
+
+/** --config、SUMMALY_CONFIG_PATH、config.jsonの順に設定ファイルを決め、--configを取り除いた引数を返す*/
+pub fn config_path(mut args:Vec<String>)->(String,Vec<String>){
+	let mut path=None;
+	if let Some(idx)=args.iter().position(|a|a=="--config"){
+		if idx+1<args.len(){
+			path=Some(args.remove(idx+1));
+		}
+		args.remove(idx);
+	}
+	let path=path.or_else(||std::env::var("SUMMALY_CONFIG_PATH").ok().filter(|p|!p.is_empty()));
+	(path.unwrap_or_else(||"config.json".to_owned()),args)
+}
+/** --init-config 既定の設定ファイルを書き出す*/
+pub fn init_config(path:&str)->i32{
+	if std::path::Path::new(path).exists(){
+		eprintln!("{}: already exists",path);
+		return 1;
+	}
+	let default_config=ConfigFile::default();
+	let s=if is_toml(path){
+		toml::to_string_pretty(&default_config).map_err(|e|e.to_string())
+	}else{
+		serde_json::to_string_pretty(&default_config).map_err(|e|e.to_string())
+	};
+	let res=s.and_then(|s|{
+		std::fs::File::create(path).and_then(|mut f|f.write_all(s.as_bytes())).map_err(|e|e.to_string())
+	});
+	match res{
+		Ok(_)=>{
+			println!("{}: created",path);
+			0
+		},
+		Err(e)=>{
+			eprintln!("{}: {}",path,e);
+			1
+		}
+	}
+}
+/** --check-config 設定を読み込んで問題があれば全て表示する*/
+pub fn check_config(path:&str)->i32{
+	match load(path){
+		Ok(_)=>{
+			println!("{}: ok",path);
+			0
+		},
+		Err(errors)=>{
+			for e in errors{
+				eprintln!("{}: {}",path,e);
+			}
+			1
+		}
+	}
+}
+/** 設定ファイルを読み込み、環境変数で上書きして検証する。ファイルが無い場合は既定値を使う*/
+pub fn load(path:&str)->Result<ConfigFile,Vec<String>>{
+	let mut value=read_file(path).map_err(|e|vec![e])?;
+	apply_env(&mut value,std::env::vars()).map_err(|e|vec![e])?;
+	let config:ConfigFile=serde_path_to_error::deserialize(value).map_err(|e|{
+		vec![format!("{}: {}",e.path(),e.inner())]
+	})?;
+	let errors=validate(&config);
+	if errors.is_empty(){
+		Ok(config)
+	}else{
+		Err(errors)
+	}
+}
+fn is_toml(path:&str)->bool{
+	path.ends_with(".toml")
+}
+fn read_file(path:&str)->Result<serde_json::Value,String>{
+	let s=match std::fs::read_to_string(path){
+		Ok(s)=>s,
+		Err(e) if e.kind()==std::io::ErrorKind::NotFound=>{
+			eprintln!("{}: not found, using defaults",path);
+			return Ok(serde_json::json!({}));
+		},
+		Err(e)=>return Err(e.to_string()),
+	};
+	if is_toml(path){
+		toml::from_str(&s).map_err(|e|e.to_string())
+	}else{
+		serde_json::from_str(&s).map_err(|e|e.to_string())
+	}
+}
+/** 環境変数の値をどの型として読むか決めるための見本。既定値がnullのキーには同じ型の値を入れておく*/
+fn type_hints()->Result<serde_json::Value,String>{
+	let mut hints=serde_json::to_value(ConfigFile::default()).map_err(|e|e.to_string())?;
+	let samples=serde_json::json!({
+		"unix_socket_mode":"",
+		"proxy":"",
+		"media_proxy":"",
+		"client_rate_limit":{"rate":0.0,"burst":0,"trusted_proxies":[]},
+		"auth":{"keys":[],"hmac_secret":""},
+		"metrics":{"bind_addr":"","path":""},
+		"favicon_probe":crate::favicon::FaviconProbeConfig::default(),
+		"thumbnail_probe":crate::thumbnail::ThumbnailProbeConfig::default(),
+	});
+	for (k,v) in samples.as_object().unwrap(){
+		if hints[k].is_null(){
+			hints[k]=v.clone();
+		}
+	}
+	Ok(hints)
+}
+/** SUMMALY_で始まる環境変数で設定の値を置き換える*/
+fn apply_env(value:&mut serde_json::Value,vars:impl Iterator<Item=(String,String)>)->Result<(),String>{
+	let hints=type_hints()?;
+	for (k,v) in vars{
+		let Some(key)=k.strip_prefix(ENV_PREFIX) else{
+			continue;
+		};
+		if key=="CONFIG_PATH"{
+			continue;
+		}
+		let path:Vec<String>=key.split("__").map(|s|s.to_lowercase()).collect();
+		if hints.get(&path[0]).is_none(){
+			eprintln!("{}: unknown config key {}",k,path[0]);
+			continue;
+		}
+		//JSONの配列やオブジェクトはそのまま使う。それ以外は数値や真偽値のキーのみ解釈し、型の分からないキーは文字列にする
+		let hint=path.iter().try_fold(&hints,|v,k|v.get(k));
+		let v=match (hint,serde_json::from_str(&v)){
+			(_,Ok(parsed@(serde_json::Value::Array(_)|serde_json::Value::Object(_))))=>parsed,
+			(Some(serde_json::Value::Number(_)),Ok(parsed@serde_json::Value::Number(_)))=>parsed,
+			(Some(serde_json::Value::Bool(_)),Ok(parsed@serde_json::Value::Bool(_)))=>parsed,
+			_=>serde_json::Value::String(v),
+		};
+		let mut target=&mut *value;
+		for k in path.iter(){
+			if !target.is_object(){
+				*target=serde_json::json!({});
+			}
+			target=target.as_object_mut().unwrap().entry(k.clone()).or_insert(serde_json::Value::Null);
+		}
+		*target=v;
+	}
+	Ok(())
+}
+/** 型は正しいが使えない値を探す*/
+fn validate(config:&ConfigFile)->Vec<String>{
+	let mut errors=vec![];
+	for addr in config.bind_addr.addrs(){
+		if let Err(e)=crate::listen::parse_addr(addr){
+			errors.push(format!("bind_addr: {}: {}",addr,e));
+		}
+	}
+	if let Some(mode)=&config.unix_socket_mode{
+		if let Err(e)=u32::from_str_radix(mode,8){
+			errors.push(format!("unix_socket_mode: {}",e));
+		}
+	}
+	if config.timeout==0{
+		errors.push("timeout: must be greater than 0".to_owned());
+	}
+	if config.max_size==0{
+		errors.push("max_size: must be greater than 0".to_owned());
+	}
+	if let Some(proxy)=&config.proxy{
+		if let Err(e)=reqwest::Proxy::all(proxy){
+			errors.push(format!("proxy: {}",e));
+		}
+	}
+	if let Some(media_proxy)=&config.media_proxy{
+		if let Err(e)=reqwest::Url::parse(media_proxy){
+			errors.push(format!("media_proxy: {}",e));
+		}
+	}
+	for (i,line) in config.append_headers.iter().enumerate(){
+		if let Err(e)=crate::parse_header_line(line){
+			errors.push(format!("append_headers[{}]: {}",i,e));
+		}
+	}
+	errors.extend(config.headers.validate());
+	errors.extend(config.compression.validate());
+	errors.extend(config.player.validate());
+	if let Some(client_rate_limit)=&config.client_rate_limit{
+		if client_rate_limit.rate.is_nan()||client_rate_limit.rate<=0.0{
+			errors.push("client_rate_limit.rate: must be greater than 0".to_owned());
+		}
+		if client_rate_limit.burst==0{
+			errors.push("client_rate_limit.burst: must be greater than 0".to_owned());
+		}
+	}
+	if let Some(metrics)=&config.metrics{
+		if let Some(bind_addr)=&metrics.bind_addr{
+			if let Err(e)=bind_addr.parse::<std::net::SocketAddr>(){
+				errors.push(format!("metrics.bind_addr: {}",e));
+			}
+		}
+		if !metrics.path.starts_with('/'){
+			errors.push("metrics.path: must start with /".to_owned());
+		}
+	}
+	if let Err(e)=tracing_subscriber::EnvFilter::try_new(&config.log.level){
+		errors.push(format!("log.level: {}",e));
+	}
+	if let Some(favicon_probe)=&config.favicon_probe{
+		if favicon_probe.timeout==0{
+			errors.push("favicon_probe.timeout: must be greater than 0".to_owned());
+		}
+	}
+	if let Some(thumbnail_probe)=&config.thumbnail_probe{
+		if thumbnail_probe.timeout==0{
+			errors.push("thumbnail_probe.timeout: must be greater than 0".to_owned());
+		}
+		if thumbnail_probe.header_size==0{
+			errors.push("thumbnail_probe.header_size: must be greater than 0".to_owned());
+		}
+	}
+	if config.icon_size==0{
+		errors.push("icon_size: must be greater than 0".to_owned());
+	}
+	if config.batch_max_size==0{
+		errors.push("batch_max_size: must be greater than 0".to_owned());
+	}
+	errors
+}
+/** SIGHUPで差し替えられる設定。処理中のリクエストは読み込んだ時点の設定を使い続ける*/
+#[derive(Clone,Debug)]
+pub struct SharedConfig(std::sync::Arc<std::sync::RwLock<std::sync::Arc<ConfigFile>>>);
+impl SharedConfig{
+	pub fn new(config:std::sync::Arc<ConfigFile>)->Self{
+		Self(std::sync::Arc::new(std::sync::RwLock::new(config)))
+	}
+	pub fn load(&self)->std::sync::Arc<ConfigFile>{
+		self.0.read().unwrap().clone()
+	}
+	fn store(&self,config:std::sync::Arc<ConfigFile>){
+		*self.0.write().unwrap()=config;
+	}
+}
+/** 起動時にしか読まないため、変更しても再起動するまで反映されないキー*/
+fn restart_required(old:&ConfigFile,new:&ConfigFile)->Vec<&'static str>{
+	let mut keys=vec![];
+	//上流へのHTTPクライアント
+	if old.proxy!=new.proxy{
+		keys.push("proxy");
+	}
+	//待ち受けるソケット
+	if old.bind_addr!=new.bind_addr{
+		keys.push("bind_addr");
+	}
+	if old.unix_socket_mode!=new.unix_socket_mode{
+		keys.push("unix_socket_mode");
+	}
+	match (&old.metrics,&new.metrics){
+		(Some(old),Some(new))=>{
+			if old.bind_addr!=new.bind_addr{
+				keys.push("metrics.bind_addr");
+			}
+			if old.path!=new.path{
+				keys.push("metrics.path");
+			}
+		},
+		(None,None)=>{},
+		_=>keys.push("metrics"),
+	}
+	if serde_json::to_value(&old.compression).ok()!=serde_json::to_value(&new.compression).ok(){
+		keys.push("compression");
+	}
+	if old.log.format!=new.log.format{
+		keys.push("log.format");
+	}
+	if old.log.level!=new.log.level{
+		keys.push("log.level");
+	}
+	keys
+}
+/** SIGHUPを受け取る度に設定を読み直す。問題がある場合は今の設定を使い続ける*/
+pub async fn reload_on_sighup(path:String,shared:SharedConfig){
+	#[cfg(unix)]
+	{
+		use tokio::signal;
+		let mut hangup=signal::unix::signal(signal::unix::SignalKind::hangup()).expect("failed to install signal handler");
+		while hangup.recv().await.is_some(){
+			match load(&path){
+				Ok(config)=>{
+					let keys=restart_required(&shared.load(),&config);
+					if !keys.is_empty(){
+						tracing::warn!(keys=?keys,"config changes require restart");
+					}
+					shared.store(std::sync::Arc::new(config));
+					tracing::info!(path=%path,"config reloaded");
+				},
+				Err(errors)=>{
+					for e in errors{
+						tracing::error!(path=%path,error=%e,"config reload failed");
+					}
+				}
+			}
+		}
+	}
+	#[cfg(not(unix))]
+	{
+		let _=(path,shared);
+	}
+}
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn load_env(value:serde_json::Value,vars:&[(&str,&str)])->Result<ConfigFile,String>{
+		let mut value=value;
+		apply_env(&mut value,vars.iter().map(|(k,v)|(k.to_string(),v.to_string())))?;
+		serde_path_to_error::deserialize(value).map_err(|e|format!("{}: {}",e.path(),e.inner()))
+	}
+	#[test]
+	fn env_follows_default_types(){
+		let config=load_env(serde_json::json!({}),&[
+			("SUMMALY_TIMEOUT","3000"),
+			("SUMMALY_USER_AGENT","12345"),
+			("SUMMALY_WEB_APP_MANIFEST","false"),
+			("SUMMALY_UNIX_SOCKET_MODE","660"),
+			("SUMMALY_AUTH__HMAC_SECRET","123456"),
+			("SUMMALY_CLIENT_RATE_LIMIT__RATE","5"),
+			("SUMMALY_CLIENT_RATE_LIMIT__BURST","10"),
+			("SUMMALY_LOG__FORMAT","json"),
+		]).unwrap();
+		assert_eq!(config.timeout,3000);
+		assert_eq!(config.user_agent,"12345");
+		assert!(!config.web_app_manifest);
+		assert_eq!(config.unix_socket_mode.as_deref(),Some("660"));
+		assert!(config.auth.is_some());
+		assert_eq!(config.client_rate_limit.as_ref().map(|c|c.burst),Some(10));
+		assert_eq!(config.log.format,crate::logging::LogFormat::Json);
+	}
+	#[test]
+	fn env_json_values(){
+		let config=load_env(serde_json::json!({}),&[
+			("SUMMALY_BIND_ADDR",r#"["127.0.0.1:12267","[::1]:12267"]"#),
+			("SUMMALY_METRICS",r#"{"path":"/m"}"#),
+		]).unwrap();
+		assert_eq!(config.bind_addr.addrs(),vec!["127.0.0.1:12267","[::1]:12267"]);
+		assert_eq!(config.metrics.map(|m|m.path).as_deref(),Some("/m"));
+		//数値のキーに数値以外を指定すると型の誤りとして報告する
+		let res=load_env(serde_json::json!({}),&[("SUMMALY_TIMEOUT","5s")]);
+		assert!(res.unwrap_err().starts_with("timeout:"));
+	}
+	#[test]
+	fn unknown_fields_rejected(){
+		let res=load_env(serde_json::json!({"timeuot":3000}),&[]);
+		assert!(res.is_err());
+		let res=load_env(serde_json::json!({"log":{"fromat":"json"}}),&[]);
+		assert!(res.unwrap_err().starts_with("log"));
+		let res=load_env(serde_json::json!({"auth":{"keys":[{"name":"a","key":"b","ratelimit":null}]}}),&[]);
+		assert!(res.is_err());
+	}
+}
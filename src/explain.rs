@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+tokio::task_local!{
+	/** 実行中のリクエストが辿ったリダイレクト先*/
+	static REDIRECTS:std::cell::RefCell<Vec<String>>;
+}
+/** リダイレクト先を記録する以外はreqwestの既定と同じ*/
+pub fn redirect_policy()->reqwest::redirect::Policy{
+	reqwest::redirect::Policy::custom(|attempt|{
+		let _=REDIRECTS.try_with(|r|r.borrow_mut().push(attempt.url().to_string()));
+		if attempt.previous().len()>=10{
+			attempt.error("too many redirects")
+		}else{
+			attempt.follow()
+		}
+	})
+}
+/** fの実行中に辿ったリダイレクト先を集める*/
+pub async fn with_redirects<F:std::future::Future>(f:F)->(F::Output,Vec<String>){
+	REDIRECTS.scope(std::cell::RefCell::new(vec![]),async{
+		let out=f.await;
+		(out,REDIRECTS.with(|r|r.take()))
+	}).await
+}
+/** debug=1の時に_explainとして返す、各フィールドの値の出どころ*/
+#[derive(Debug,Default,Serialize)]
+pub struct Explain{
+	#[serde(skip)]
+	enabled:bool,
+	fields:BTreeMap<&'static str,FieldExplain>,
+	charset:Option<Charset>,
+	/** 処理毎の所要時間(ms)*/
+	timings:BTreeMap<&'static str,f64>,
+	redirects:Vec<String>,
+}
+#[derive(Debug,Default,Serialize)]
+struct FieldExplain{
+	candidates:Vec<Candidate>,
+	/** 出力に使われた候補の添字*/
+	winner:Option<usize>,
+}
+#[derive(Debug,Serialize)]
+struct Candidate{
+	source:String,
+	raw:String,
+	resolved:String,
+	/** 値が見つかったが使えなかった理由*/
+	#[serde(skip_serializing_if="Option::is_none")]
+	rejected:Option<String>,
+	#[serde(skip)]
+	applied:bool,
+}
+#[derive(Debug,Serialize)]
+struct Charset{
+	encoding:String,
+	source:&'static str,
+}
+impl Explain{
+	pub fn new(enabled:bool)->Self{
+		Self{
+			enabled,
+			..Default::default()
+		}
+	}
+	pub fn enabled(&self)->bool{
+		self.enabled
+	}
+	/** 候補を記録する。appliedは出力に反映された場合で、最後に反映されたものが勝つ*/
+	pub fn candidate(&mut self,field:&'static str,source:&str,raw:&str,resolved:&str,applied:bool){
+		if !self.enabled{
+			return;
+		}
+		self.fields.entry(field).or_default().candidates.push(Candidate{
+			source:source.to_owned(),
+			raw:raw.to_owned(),
+			resolved:resolved.to_owned(),
+			rejected:None,
+			applied,
+		});
+	}
+	/** 直前に記録した候補を使わなかった理由を記録する*/
+	pub fn reject(&mut self,field:&'static str,reason:&str){
+		if let Some(c)=self.fields.get_mut(field).and_then(|f|f.candidates.last_mut()){
+			c.rejected=Some(reason.to_owned());
+			c.applied=false;
+		}
+	}
+	/** URLを解決した後の値に置き換える*/
+	pub fn resolve(&mut self,field:&'static str,f:impl Fn(&str)->Option<String>){
+		if let Some(field)=self.fields.get_mut(field){
+			for c in field.candidates.iter_mut(){
+				if let Some(resolved)=f(&c.resolved){
+					c.resolved=resolved;
+				}
+			}
+		}
+	}
+	pub fn charset(&mut self,encoding:&str,source:&'static str){
+		self.charset=Some(Charset{
+			encoding:encoding.to_owned(),
+			source,
+		});
+	}
+	pub fn timing(&mut self,phase:&'static str,elapsed:std::time::Duration){
+		*self.timings.entry(phase).or_insert(0.0)+=elapsed.as_secs_f64()*1000.0;
+	}
+	pub fn redirects(&mut self,redirects:Vec<String>){
+		self.redirects=redirects;
+	}
+	/** 勝った候補を決めて出力する。無効な場合はNone*/
+	pub fn finish(mut self)->Option<Self>{
+		if !self.enabled{
+			return None;
+		}
+		for field in self.fields.values_mut(){
+			field.winner=field.candidates.iter().rposition(|c|c.applied);
+		}
+		Some(self)
+	}
+}
@@ -0,0 +1,79 @@
+use std::{collections::HashMap, sync::{LazyLock, Mutex}, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{metrics::METRICS, subrequest::Subrequest};
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaviconProbeConfig{
+	/** 確認を諦めるまでの時間(ms)*/
+	#[serde(default="default_timeout")]
+	pub timeout:u64,
+	/** オリジン毎の結果を覚えておく時間(ms)*/
+	#[serde(default="default_cache_ttl")]
+	pub cache_ttl:u64,
+	/** 結果を覚えておくオリジンの数*/
+	#[serde(default="default_cache_size")]
+	pub cache_size:usize,
+}
+fn default_timeout()->u64{
+	1000
+}
+fn default_cache_ttl()->u64{
+	60*60*1000
+}
+fn default_cache_size()->usize{
+	10000
+}
+impl Default for FaviconProbeConfig{
+	fn default()->Self{
+		Self{
+			timeout:default_timeout(),
+			cache_ttl:default_cache_ttl(),
+			cache_size:default_cache_size(),
+		}
+	}
+}
+/** オリジン毎の(期限,/favicon.icoが存在するか)*/
+static CACHE:LazyLock<Mutex<HashMap<String,(Instant,bool)>>>=LazyLock::new(||Mutex::new(HashMap::new()));
+/** {origin}/favicon.icoが画像を返すか確かめる*/
+pub async fn probe(sub:&Subrequest<'_>,origin:&str,config:&FaviconProbeConfig)->bool{
+	let now=Instant::now();
+	if let Some((expires,exists))=CACHE.lock().unwrap().get(origin).copied(){
+		if expires>now{
+			METRICS.favicon_cache(true);
+			return exists;
+		}
+	}
+	METRICS.favicon_cache(false);
+	let url=format!("{}/favicon.ico",origin);
+	let deadline=sub.deadline(Some(Duration::from_millis(config.timeout)));
+	let mut res=sub.send(sub.client.head(&url),&url,deadline).await;
+	//HEADに対応していないサーバー
+	if matches!(&res,Ok((r,_)) if r.status()==reqwest::StatusCode::METHOD_NOT_ALLOWED||r.status()==reqwest::StatusCode::NOT_IMPLEMENTED){
+		res=sub.send(sub.client.get(&url),&url,deadline).await;
+	}
+	let exists=match res{
+		Ok((res,_))=>{
+			let content_type=res.headers().get(reqwest::header::CONTENT_TYPE).and_then(|v|v.to_str().ok()).unwrap_or_default();
+			let content_type=content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+			//.icoは種類が分からないものとして返すサーバーも多い。HTMLのエラーページ等は除く
+			res.status().is_success()&&(content_type.is_empty()||content_type.starts_with("image/")||content_type=="application/octet-stream")
+		},
+		Err(e)=>{
+			//締め切りや流量制限で確かめられなかった場合は覚えない。接続できないホストはサーキットブレーカーで止まる
+			tracing::debug!(url=url,error=%e,"favicon probe");
+			return false;
+		}
+	};
+	let mut cache=CACHE.lock().unwrap();
+	if cache.len()>=config.cache_size{
+		cache.retain(|_,(expires,_)|*expires>now);
+		if cache.len()>=config.cache_size{
+			cache.clear();
+		}
+	}
+	cache.insert(origin.to_owned(),(now+Duration::from_millis(config.cache_ttl),exists));
+	exists
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::SharedConfig;
+
+/** 応答の種類毎に付けるヘッダ*/
+#[derive(Clone,Debug,Default,Serialize,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct HeadersConfig{
+	/** nullの場合はCORSのヘッダを付けず、OPTIONSにも応答しない*/
+	pub cors:Option<CorsConfig>,
+	pub content_security_policy:Option<String>,
+	/** 全ての応答に付けるヘッダ。例: {"X-Content-Type-Options":"nosniff"}*/
+	pub security:BTreeMap<String,String>,
+	/** 2xxの応答に付けるヘッダ*/
+	pub success:BTreeMap<String,String>,
+	/** 4xxの応答に付けるヘッダ*/
+	pub client_error:BTreeMap<String,String>,
+	/** 5xxの応答に付けるヘッダ*/
+	pub upstream_error:BTreeMap<String,String>,
+}
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct CorsConfig{
+	/** "*"で全てのオリジンを許可する*/
+	pub allowed_origins:Vec<String>,
+	pub allowed_methods:Vec<String>,
+	pub allowed_headers:Vec<String>,
+	/** プリフライトの結果をキャッシュしてよい秒数*/
+	pub max_age:Option<u64>,
+}
+impl Default for CorsConfig{
+	fn default()->Self{
+		Self{
+			allowed_origins:vec!["*".to_owned()],
+			allowed_methods:vec!["GET".to_owned(),"POST".to_owned(),"OPTIONS".to_owned()],
+			allowed_headers:vec!["Authorization".to_owned(),"Content-Type".to_owned()],
+			max_age:Some(86400),
+		}
+	}
+}
+impl HeadersConfig{
+	/** 設定の名前と値を全て検証する*/
+	pub fn validate(&self)->Vec<String>{
+		let mut errors=vec![];
+		let maps=[
+			("security",&self.security),
+			("success",&self.success),
+			("client_error",&self.client_error),
+			("upstream_error",&self.upstream_error),
+		];
+		for (class,map) in maps{
+			for (k,v) in map.iter(){
+				if let Err(e)=parse_header(k,v){
+					errors.push(format!("headers.{}.{}: {}",class,k,e));
+				}
+			}
+		}
+		if let Some(csp)=&self.content_security_policy{
+			if let Err(e)=axum::http::HeaderValue::from_str(csp){
+				errors.push(format!("headers.content_security_policy: {}",e));
+			}
+		}
+		if let Some(cors)=&self.cors{
+			for (key,values) in [("allowed_origins",&cors.allowed_origins),("allowed_methods",&cors.allowed_methods),("allowed_headers",&cors.allowed_headers)]{
+				for v in values.iter(){
+					if let Err(e)=axum::http::HeaderValue::from_str(v){
+						errors.push(format!("headers.cors.{}: {}: {}",key,v,e));
+					}
+				}
+			}
+			for m in cors.allowed_methods.iter(){
+				if let Err(e)=axum::http::Method::from_bytes(m.as_bytes()){
+					errors.push(format!("headers.cors.allowed_methods: {}: {}",m,e));
+				}
+			}
+		}
+		errors
+	}
+	fn apply(&self,status:axum::http::StatusCode,origin:Option<&axum::http::HeaderValue>,headers:&mut axum::http::HeaderMap){
+		let class=if status.is_success()||status.is_informational()||status.is_redirection(){
+			&self.success
+		}else if status.is_client_error(){
+			&self.client_error
+		}else{
+			&self.upstream_error
+		};
+		for (k,v) in self.security.iter().chain(class.iter()){
+			if let Ok((k,v))=parse_header(k,v){
+				headers.insert(k,v);
+			}
+		}
+		if let Some(csp)=self.content_security_policy.as_ref().and_then(|v|v.parse().ok()){
+			headers.insert(axum::http::header::CONTENT_SECURITY_POLICY,csp);
+		}
+		if let Some(cors)=&self.cors{
+			if let Some(origin)=cors.allow_origin(origin){
+				headers.insert(axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,origin);
+			}
+			//許可しないオリジンやOriginが無いリクエストへの応答もキャッシュで使い回されないようにする
+			if cors.allowed_origins.iter().all(|o|o!="*"){
+				headers.append(axum::http::header::VARY,"Origin".parse().unwrap());
+			}
+		}
+	}
+}
+impl CorsConfig{
+	/** Access-Control-Allow-Originに返す値。許可しないオリジンはNone*/
+	fn allow_origin(&self,origin:Option<&axum::http::HeaderValue>)->Option<axum::http::HeaderValue>{
+		if self.allowed_origins.iter().any(|o|o=="*"){
+			return Some(axum::http::HeaderValue::from_static("*"));
+		}
+		let origin=origin?;
+		let s=origin.to_str().ok()?;
+		self.allowed_origins.iter().any(|o|o.eq_ignore_ascii_case(s)).then(||origin.clone())
+	}
+	fn preflight(&self,headers:&mut axum::http::HeaderMap){
+		if let Ok(v)=self.allowed_methods.join(", ").parse(){
+			headers.insert(axum::http::header::ACCESS_CONTROL_ALLOW_METHODS,v);
+		}
+		if let Ok(v)=self.allowed_headers.join(", ").parse(){
+			headers.insert(axum::http::header::ACCESS_CONTROL_ALLOW_HEADERS,v);
+		}
+		if let Some(max_age)=self.max_age{
+			headers.insert(axum::http::header::ACCESS_CONTROL_MAX_AGE,max_age.into());
+		}
+	}
+}
+fn parse_header(k:&str,v:&str)->Result<(axum::http::HeaderName,axum::http::HeaderValue),String>{
+	let k=axum::http::HeaderName::from_bytes(k.as_bytes()).map_err(|e|e.to_string())?;
+	let v=axum::http::HeaderValue::from_str(v).map_err(|e|e.to_string())?;
+	Ok((k,v))
+}
+/** 応答のステータスに応じたヘッダを付け、CORSのプリフライトに応答する*/
+pub async fn apply_headers(config:SharedConfig,req:axum::extract::Request,next:axum::middleware::Next)->axum::response::Response{
+	let config=config.load();
+	let origin=req.headers().get(axum::http::header::ORIGIN).cloned();
+	let mut res=match &config.headers.cors{
+		Some(cors) if req.method()==axum::http::Method::OPTIONS=>{
+			let mut res=axum::response::IntoResponse::into_response(axum::http::StatusCode::NO_CONTENT);
+			cors.preflight(res.headers_mut());
+			res
+		},
+		_=>next.run(req).await,
+	};
+	let status=res.status();
+	config.headers.apply(status,origin.as_ref(),res.headers_mut());
+	config.append_headers(res.headers_mut());
+	res
+}
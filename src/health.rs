@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use axum::response::IntoResponse;
+
+use crate::{ConfigFile, RequestParams};
+
+const TEST_HTML:&str=r#"
+<html><head>
+<meta property="og:description" content="description text">
+<title>TEST_HTML_FILE</title>
+</head></html>
+"#;
+
+/** プロセスが動いている*/
+pub async fn healthz()->axum::response::Response{
+	(axum::http::StatusCode::OK,"ok").into_response()
+}
+/** リクエストを受け付けられる。設定で有効なら組み込みのHTMLを要約できるか確認する*/
+pub async fn readyz(client:reqwest::Client,config:std::sync::Arc<ConfigFile>)->axum::response::Response{
+	if config.readiness_self_test{
+		if let Err(e)=self_test(&client,&config).await{
+			tracing::error!(error=%e,"readiness self test");
+			let mut headers=axum::http::HeaderMap::new();
+			headers.append("X-Proxy-Error",e.parse().unwrap());
+			return (axum::http::StatusCode::SERVICE_UNAVAILABLE,headers).into_response();
+		}
+	}
+	(axum::http::StatusCode::OK,"ok").into_response()
+}
+async fn self_test(client:&reqwest::Client,config:&ConfigFile)->Result<(),String>{
+	let q=RequestParams{
+		url:"http://localhost/".to_owned(),
+		lang:None,
+		user_agent:None,
+		response_timeout:None,
+		content_length_limit:None,
+		key:None,
+		expires:None,
+		signature:None,
+		debug:None,
+	};
+	//組み込みのHTMLの要約だけを確かめるので外部への取得はしない
+	let config=ConfigFile{
+		favicon_probe:None,
+		thumbnail_probe:None,
+		web_app_manifest:false,
+		..config.clone()
+	};
+	let deadline=tokio::time::Instant::now()+std::time::Duration::from_millis(config.timeout);
+	let sub=crate::subrequest::Subrequest::new(client,None,None,&config,&q,deadline);
+	let resp=crate::summarize(TEST_HTML.as_bytes(),&q,&sub,crate::explain::Explain::new(false)).await?;
+	if resp.title.as_deref()!=Some("TEST_HTML_FILE"){
+		return Err(format!("title:{:?}",resp.title));
+	}
+	if resp.description.as_deref()!=Some("description text"){
+		return Err(format!("description:{:?}",resp.description));
+	}
+	Ok(())
+}
+/** --healthcheck 起動中のサーバーの/readyzを確認して終了コードを返す*/
+pub fn healthcheck(config:&ConfigFile)->i32{
+	let Some(addr)=config.bind_addr.addrs().first().copied() else{
+		eprintln!("bind_addr is empty");
+		return 1;
+	};
+	let addr=match crate::listen::parse_addr(addr){
+		Ok(addr)=>addr,
+		Err(e)=>{
+			eprintln!("bind_addr {}",e);
+			return 1;
+		}
+	};
+	let rt=tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+	let timeout=std::time::Duration::from_millis(500);
+	let client=reqwest::Client::builder().timeout(timeout).build().unwrap();
+	for _ in 0..5{
+		let status=rt.block_on(async{
+			match &addr{
+				crate::listen::Addr::Tcp(addr)=>{
+					let mut addr=*addr;
+					if addr.ip().is_unspecified(){
+						//全てのアドレスで待ち受けている場合はループバックに接続する
+						match addr{
+							SocketAddr::V4(_)=>addr.set_ip(std::net::Ipv4Addr::LOCALHOST.into()),
+							SocketAddr::V6(_)=>addr.set_ip(std::net::Ipv6Addr::LOCALHOST.into()),
+						}
+					}
+					match client.get(format!("http://{}/readyz",addr)).send().await{
+						Ok(s)=>s.status().as_u16(),
+						Err(_)=>504,
+					}
+				},
+				crate::listen::Addr::Unix(path)=>{
+					tokio::time::timeout(timeout,readyz_unix(path)).await.ok().flatten().unwrap_or(504)
+				},
+			}
+		});
+		if status==200{
+			println!("ok");
+			return 0;
+		}
+		std::thread::sleep(std::time::Duration::from_millis(500));
+	}
+	println!("not ready");
+	1
+}
+/** reqwestはUnixドメインソケットに接続できないので直接HTTP/1.1で問い合わせる*/
+#[cfg(unix)]
+async fn readyz_unix(path:&str)->Option<u16>{
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	let mut stream=tokio::net::UnixStream::connect(path).await.ok()?;
+	stream.write_all(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.ok()?;
+	let mut buf=vec![0;64];
+	let len=stream.read(&mut buf).await.ok()?;
+	//HTTP/1.1 200 OK
+	std::str::from_utf8(&buf[..len]).ok()?.split(' ').nth(1)?.parse().ok()
+}
+#[cfg(not(unix))]
+async fn readyz_unix(_path:&str)->Option<u16>{
+	None
+}
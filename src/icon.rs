@@ -0,0 +1,109 @@
+/** linkタグから集めたアイコンの候補*/
+#[derive(Clone,Debug)]
+pub struct IconCandidate{
+	pub href:String,
+	/** explainに出す取得元*/
+	pub source:String,
+	sizes:Vec<u32>,
+	/** sizes="any"。SVG等の拡大縮小できるアイコン*/
+	scalable:bool,
+	mime:Option<String>,
+	/** relにapple-touch-iconかapple-touch-icon-precomposedを含む*/
+	apple:bool,
+}
+impl IconCandidate{
+	/** relにアイコンを表すトークンが含まれていれば候補にする*/
+	pub fn from_link(rel:&str,href:&str,sizes:Option<&str>,mime:Option<&str>)->Option<Self>{
+		let rel=rel.to_ascii_lowercase();
+		let tokens:Vec<&str>=rel.split_ascii_whitespace().collect();
+		let apple=tokens.iter().any(|t|*t=="apple-touch-icon"||*t=="apple-touch-icon-precomposed");
+		if !apple&&!tokens.contains(&"icon"){
+			return None;
+		}
+		let mut icon=Self::new(href,format!("link[rel={}]",rel),sizes,mime);
+		icon.apple=apple;
+		//sizesが無いapple-touch-iconは180x180が標準
+		if apple&&icon.sizes.is_empty(){
+			icon.sizes.push(180);
+		}
+		Some(icon)
+	}
+	/** Web App Manifestのicons。maskableやmonochrome専用のものは余白や色が異なるので使わない*/
+	pub fn from_manifest(src:&str,sizes:Option<&str>,mime:Option<&str>,purpose:Option<&str>)->Option<Self>{
+		let purpose=purpose.unwrap_or("any");
+		if !purpose.split_ascii_whitespace().any(|p|p.eq_ignore_ascii_case("any")){
+			return None;
+		}
+		Some(Self::new(src,"manifest".to_owned(),sizes,mime))
+	}
+	fn new(href:&str,source:String,sizes:Option<&str>,mime:Option<&str>)->Self{
+		let mut sizes_list=vec![];
+		let mut scalable=false;
+		for size in sizes.unwrap_or_default().split_ascii_whitespace(){
+			if size.eq_ignore_ascii_case("any"){
+				scalable=true;
+			}else if let Some((w,h))=size.to_ascii_lowercase().split_once('x'){
+				if let (Ok(w),Ok(h))=(w.parse::<u32>(),h.parse::<u32>()){
+					sizes_list.push(w.max(h));
+				}
+			}
+		}
+		Self{
+			href:href.to_owned(),
+			source,
+			sizes:sizes_list,
+			scalable,
+			mime:mime.map(|s|s.to_ascii_lowercase()),
+			apple:false,
+		}
+	}
+	pub fn is_apple_touch_icon(&self)->bool{
+		self.apple
+	}
+	fn format_rank(&self)->u8{
+		let ext=self.href.split(['?','#']).next().unwrap_or_default().rsplit('.').next().unwrap_or_default().to_ascii_lowercase();
+		match (self.mime.as_deref(),ext.as_str()){
+			(Some("image/svg+xml"),_)|(Some("image/png"),_)|(None,"svg")|(None,"png")=>0,
+			(Some("image/x-icon"),_)|(Some("image/vnd.microsoft.icon"),_)|(None,"ico")=>2,
+			_=>1,
+		}
+	}
+	/** 目標の大きさに最も近い寸法。sizesが無いものはfavicon相当とみなす*/
+	fn size(&self,target:u32)->u32{
+		if self.scalable{
+			return target;
+		}
+		let larger=self.sizes.iter().filter(|s|**s>=target).min();
+		larger.or_else(||self.sizes.iter().max()).copied().unwrap_or(16)
+	}
+	/** 目標の大きさを満たしている*/
+	pub fn is_large_enough(&self,target:u32)->bool{
+		self.size(target)>=target
+	}
+	/** 目標以上で小さいもの、無ければ大きいもの、同じなら形式で比べる*/
+	fn score(&self,target:u32)->(bool,u32,u8){
+		let size=self.size(target);
+		(size<target,size.abs_diff(target),self.format_rank())
+	}
+}
+/** 同点の場合は文書中で先に現れたものを使う*/
+pub fn best(candidates:&[IconCandidate],target:u32)->Option<usize>{
+	candidates.iter().enumerate().min_by_key(|(i,c)|(c.score(target),*i)).map(|(i,_)|i)
+}
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	#[test]
+	fn apple_touch_icon_tokens(){
+		let icon=IconCandidate::from_link("apple-touch-icon-precomposed","/a.png",None,None).unwrap();
+		assert!(icon.is_apple_touch_icon());
+		assert_eq!(icon.sizes,vec![180]);
+		let icon=IconCandidate::from_link("Shortcut Icon","/apple-touch-icon.png",None,None).unwrap();
+		assert!(!icon.is_apple_touch_icon());
+		//トークンの一部に含まれるだけのものはアイコンではない
+		assert!(IconCandidate::from_link("x-apple-touch-icon-foo","/a.png",None,None).is_none());
+		let icon=IconCandidate::from_link("icon x-apple-touch-icon-foo","/a.png",None,None).unwrap();
+		assert!(!icon.is_apple_touch_icon());
+	}
+}
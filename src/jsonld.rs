@@ -0,0 +1,166 @@
+use serde_json::{Map, Value};
+
+use crate::{normalize_time, Article};
+
+const ARTICLE_TYPES:[&str;11]=[
+	"Article",
+	"NewsArticle",
+	"BlogPosting",
+	"Report",
+	"ScholarlyArticle",
+	"TechArticle",
+	"SocialMediaPosting",
+	"LiveBlogPosting",
+	"OpinionNewsArticle",
+	"ReportageNewsArticle",
+	"AnalysisNewsArticle",
+];
+/** ページ本体ではなくサイトやパンくずを表す型*/
+const NON_CONTENT_TYPES:[&str;9]=[
+	"WebSite",
+	"Organization",
+	"BreadcrumbList",
+	"ListItem",
+	"Person",
+	"ImageObject",
+	"SearchAction",
+	"SiteNavigationElement",
+	"WPHeader",
+];
+/** JSON-LDから拾った値。いずれもmetaタグが無い場合の代替*/
+#[derive(Debug,Default)]
+pub struct JsonLd{
+	pub title:Option<String>,
+	pub description:Option<String>,
+	pub image:Option<String>,
+	pub sitename:Option<String>,
+	pub article:Option<Article>,
+}
+impl JsonLd{
+	/** headに限らずdocument全体の<script type="application/ld+json">を読む*/
+	pub fn extract(html:&str)->Self{
+		let mut items=vec![];
+		for v in scripts(html){
+			flatten(v,&mut items);
+		}
+		let primary=items.iter().find(|o|is_type(o,&ARTICLE_TYPES)).or_else(||items.iter().find(|o|!is_type(o,&NON_CONTENT_TYPES)));
+		let mut res=Self::default();
+		if let Some(primary)=primary{
+			res.title=text(primary.get("headline")).or_else(||text(primary.get("name")));
+			res.description=text(primary.get("description"));
+			res.image=image(primary.get("image")).or_else(||image(primary.get("thumbnailUrl")));
+			if is_type(primary,&ARTICLE_TYPES){
+				res.article=Some(Article{
+					headline:text(primary.get("headline")),
+					author:names(primary.get("author")),
+					published_time:text(primary.get("datePublished")).and_then(|s|normalize_time(&s)),
+					modified_time:text(primary.get("dateModified")).and_then(|s|normalize_time(&s)),
+					section:text(primary.get("articleSection")),
+					keywords:list(primary.get("keywords")),
+					..Default::default()
+				});
+			}
+		}
+		res.sitename=items.iter().find(|o|is_type(o,&["WebSite"])).and_then(|o|text(o.get("name")))
+			.or_else(||primary.and_then(|o|names(o.get("publisher"))));
+		res
+	}
+}
+fn scripts(html:&str)->Vec<Value>{
+	//ASCIIの小文字化はバイト位置を変えない
+	let lower=html.to_ascii_lowercase();
+	let mut values=vec![];
+	let mut pos=0;
+	while let Some(i)=lower[pos..].find("<script"){
+		let tag_start=pos+i;
+		let tag_end=match lower[tag_start..].find('>'){
+			Some(j)=>tag_start+j+1,
+			None=>break,
+		};
+		let body_end=match lower[tag_end..].find("</script"){
+			Some(k)=>tag_end+k,
+			None=>break,
+		};
+		pos=body_end+"</script".len();
+		if !lower[tag_start..tag_end].contains("application/ld+json"){
+			continue;
+		}
+		match serde_json::from_str(html[tag_end..body_end].trim()){
+			Ok(v)=>values.push(v),
+			Err(e)=>tracing::debug!(error=%e,"invalid json-ld"),
+		}
+	}
+	values
+}
+/** 配列と@graphを展開して@typeを持つオブジェクトを並べる*/
+fn flatten(v:Value,items:&mut Vec<Map<String,Value>>){
+	match v{
+		Value::Array(a)=>{
+			for v in a{
+				flatten(v,items);
+			}
+		},
+		Value::Object(mut o)=>{
+			let graph=o.remove("@graph");
+			if o.contains_key("@type"){
+				items.push(o);
+			}
+			if let Some(graph)=graph{
+				flatten(graph,items);
+			}
+		},
+		_=>{}
+	}
+}
+fn is_type(o:&Map<String,Value>,types:&[&str])->bool{
+	match o.get("@type"){
+		Some(Value::String(t))=>types.contains(&t.as_str()),
+		Some(Value::Array(a))=>a.iter().any(|t|t.as_str().map(|t|types.contains(&t)).unwrap_or(false)),
+		_=>false,
+	}
+}
+fn text(v:Option<&Value>)->Option<String>{
+	let s=match v?{
+		Value::String(s)=>s,
+		Value::Array(a)=>return text(a.first()),
+		_=>return None,
+	};
+	let s=html_escape::decode_html_entities(s.trim());
+	if s.is_empty(){
+		None
+	}else{
+		Some(s.into_owned())
+	}
+}
+/** "a, b"または["a","b"]*/
+fn list(v:Option<&Value>)->Vec<String>{
+	let values:Vec<&str>=match v{
+		Some(Value::String(s))=>s.split(',').collect(),
+		Some(Value::Array(a))=>a.iter().filter_map(|v|v.as_str()).collect(),
+		_=>vec![],
+	};
+	values.into_iter().map(|s|s.trim()).filter(|s|!s.is_empty()).map(|s|html_escape::decode_html_entities(s).into_owned()).collect()
+}
+/** "url"、{"url":"url"}またはそれらの配列*/
+fn image(v:Option<&Value>)->Option<String>{
+	match v?{
+		Value::String(_)=>text(v),
+		Value::Object(o)=>text(o.get("url")).or_else(||text(o.get("contentUrl"))),
+		Value::Array(a)=>a.iter().find_map(|v|image(Some(v))),
+		_=>None,
+	}
+}
+/** "name"、{"name":"name"}またはそれらの配列をカンマ区切りで*/
+fn names(v:Option<&Value>)->Option<String>{
+	let names:Vec<String>=match v?{
+		Value::String(_)=>text(v).into_iter().collect(),
+		Value::Object(o)=>text(o.get("name")).into_iter().collect(),
+		Value::Array(a)=>a.iter().filter_map(|v|names(Some(v))).collect(),
+		_=>vec![],
+	};
+	if names.is_empty(){
+		None
+	}else{
+		Some(names.join(", "))
+	}
+}
@@ -0,0 +1,190 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+/** "0.0.0.0:12267"、"unix:/run/summaly.sock"、またはそれらの配列*/
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(untagged)]
+pub enum BindAddr{
+	One(String),
+	Many(Vec<String>),
+}
+impl BindAddr{
+	pub fn addrs(&self)->Vec<&str>{
+		match self{
+			BindAddr::One(addr)=>vec![addr.as_str()],
+			BindAddr::Many(addrs)=>addrs.iter().map(|s|s.as_str()).collect(),
+		}
+	}
+}
+pub enum Addr<'a>{
+	Tcp(SocketAddr),
+	Unix(&'a str),
+}
+/** 待ち受けるアドレスを解釈する*/
+pub fn parse_addr(addr:&str)->Result<Addr<'_>,String>{
+	match addr.strip_prefix("unix:"){
+		Some("")=>Err("empty unix socket path".to_owned()),
+		Some(path)=>Ok(Addr::Unix(path)),
+		None=>addr.parse().map(Addr::Tcp).map_err(|e:std::net::AddrParseError|e.to_string()),
+	}
+}
+pub enum Listener{
+	Tcp(tokio::net::TcpListener),
+	#[cfg(unix)]
+	Unix(tokio::net::UnixListener),
+}
+/**
+ * systemdのソケットアクティベーション(LISTEN_PID/LISTEN_FDS)で渡されたソケットの数。
+ * 子プロセスに引き継がないように環境変数は消すため、他のスレッドを起動する前に呼ぶ
+ */
+pub fn take_systemd_fds()->Option<i32>{
+	let pid=std::env::var("LISTEN_PID").ok().and_then(|v|v.parse::<u32>().ok());
+	let fds=std::env::var("LISTEN_FDS").ok().and_then(|v|v.parse::<i32>().ok());
+	std::env::remove_var("LISTEN_PID");
+	std::env::remove_var("LISTEN_FDS");
+	if pid!=Some(std::process::id()){
+		return None;
+	}
+	fds.filter(|fds|*fds>0)
+}
+/** systemdから受け取ったソケットがあればそれを、無ければbind_addrの全てのアドレスで待ち受ける*/
+pub async fn bind(config:&crate::ConfigFile,systemd_fds:Option<i32>)->Result<Vec<Listener>,String>{
+	#[cfg(unix)]
+	if let Some(fds)=systemd_fds{
+		let listeners=systemd_listeners(fds)?;
+		tracing::info!(count=listeners.len(),"using sockets from systemd");
+		return Ok(listeners);
+	}
+	#[cfg(not(unix))]
+	let _=systemd_fds;
+	let mut listeners=vec![];
+	for addr in config.bind_addr.addrs(){
+		let listener=match parse_addr(addr)?{
+			Addr::Tcp(addr)=>{
+				Listener::Tcp(tokio::net::TcpListener::bind(addr).await.map_err(|e|format!("{}: {}",addr,e))?)
+			},
+			#[cfg(unix)]
+			Addr::Unix(path)=>{
+				//前回の起動で残ったソケットを消す
+				if std::fs::symlink_metadata(path).map(|m|{
+					use std::os::unix::fs::FileTypeExt;
+					m.file_type().is_socket()
+				}).unwrap_or(false){
+					std::fs::remove_file(path).map_err(|e|format!("{}: {}",path,e))?;
+				}
+				let listener=match &config.unix_socket_mode{
+					Some(mode)=>{
+						let mode=u32::from_str_radix(mode,8).map_err(|e|format!("unix_socket_mode: {}",e))?;
+						bind_unix_with_mode(path,mode).map_err(|e|format!("{}: {}",path,e))?
+					},
+					None=>tokio::net::UnixListener::bind(path).map_err(|e|format!("{}: {}",path,e))?,
+				};
+				Listener::Unix(listener)
+			},
+			#[cfg(not(unix))]
+			Addr::Unix(path)=>return Err(format!("{}: unix sockets are not supported",path)),
+		};
+		tracing::info!(addr=%addr,"listening");
+		listeners.push(listener);
+	}
+	Ok(listeners)
+}
+/**
+ * 他のユーザーから開けない一時ディレクトリの中でbindしてパーミッションを変えてから移動する。
+ * bindした直後の既定のパーミッションのソケットに接続されないようにする
+ */
+#[cfg(unix)]
+fn bind_unix_with_mode(path:&str,mode:u32)->std::io::Result<tokio::net::UnixListener>{
+	use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+	let path=std::path::Path::new(path);
+	let name=path.file_name().ok_or_else(||std::io::Error::new(std::io::ErrorKind::InvalidInput,"no file name"))?;
+	let dir=path.with_file_name(format!(".{}.{}",name.to_string_lossy(),std::process::id()));
+	std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+	let tmp=dir.join(name);
+	let res=tokio::net::UnixListener::bind(&tmp).and_then(|listener|{
+		std::fs::set_permissions(&tmp,std::fs::Permissions::from_mode(mode))?;
+		std::fs::rename(&tmp,path)?;
+		Ok(listener)
+	});
+	let _=std::fs::remove_file(&tmp);
+	let _=std::fs::remove_dir(&dir);
+	res
+}
+/** systemdから渡されたfdsのソケット*/
+#[cfg(unix)]
+fn systemd_listeners(fds:i32)->Result<Vec<Listener>,String>{
+	use std::os::fd::{FromRawFd, IntoRawFd};
+	const SD_LISTEN_FDS_START:i32=3;
+	let mut listeners=vec![];
+	for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START+fds{
+		//Safety: systemdがこのプロセスに渡したソケットで、他では使わない
+		let tcp=unsafe{std::net::TcpListener::from_raw_fd(fd)};
+		let listener=if tcp.local_addr().is_ok(){
+			tcp.set_nonblocking(true).map_err(|e|format!("fd {}: {}",fd,e))?;
+			Listener::Tcp(tokio::net::TcpListener::from_std(tcp).map_err(|e|format!("fd {}: {}",fd,e))?)
+		}else{
+			//TCPのアドレスを持たないのでUnixドメインソケット
+			let unix=unsafe{std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd())};
+			unix.set_nonblocking(true).map_err(|e|format!("fd {}: {}",fd,e))?;
+			Listener::Unix(tokio::net::UnixListener::from_std(unix).map_err(|e|format!("fd {}: {}",fd,e))?)
+		};
+		listeners.push(listener);
+	}
+	Ok(listeners)
+}
+/** 全てのソケットで待ち受け、shutdownが完了したら処理中のリクエストを待って戻る*/
+pub async fn serve(listeners:Vec<Listener>,app:axum::Router,shutdown:impl std::future::Future<Output=()>+Send+'static){
+	let token=tokio_util::sync::CancellationToken::new();
+	let mut tasks=vec![];
+	for listener in listeners{
+		let app=app.clone();
+		let token=token.clone();
+		tasks.push(tokio::spawn(async move{
+			match listener{
+				Listener::Tcp(listener)=>{
+					let res=axum::serve(listener,app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(token.cancelled_owned()).await;
+					if let Err(e)=res{
+						tracing::error!(error=%e,"serve");
+					}
+				},
+				#[cfg(unix)]
+				Listener::Unix(listener)=>serve_unix(listener,app,token).await,
+			}
+		}));
+	}
+	shutdown.await;
+	token.cancel();
+	for task in tasks{
+		let _=task.await;
+	}
+}
+#[cfg(unix)]
+async fn serve_unix(listener:tokio::net::UnixListener,app:axum::Router,token:tokio_util::sync::CancellationToken){
+	//Unixドメインソケットの接続元はループバックとして扱う
+	let app=app.layer(axum::Extension(axum::extract::ConnectInfo(SocketAddr::from(([127,0,0,1],0)))));
+	let graceful=hyper_util::server::graceful::GracefulShutdown::new();
+	let builder=hyper_util::server::conn::auto::Builder::new(hyper_util::rt::TokioExecutor::new());
+	loop{
+		let stream=tokio::select!{
+			res=listener.accept()=>match res{
+				Ok((stream,_))=>stream,
+				Err(e)=>{
+					tracing::warn!(error=%e,"accept");
+					continue;
+				}
+			},
+			_=token.cancelled()=>break,
+		};
+		let io=hyper_util::rt::TokioIo::new(stream);
+		let service=hyper_util::service::TowerToHyperService::new(app.clone());
+		let conn=builder.serve_connection_with_upgrades(io,service).into_owned();
+		let conn=graceful.watch(conn);
+		tokio::spawn(async move{
+			if let Err(e)=conn.await{
+				tracing::debug!(error=%e,"connection");
+			}
+		});
+	}
+	graceful.shutdown().await;
+}
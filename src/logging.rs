@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum LogFormat{
+	Human,
+	Json,
+}
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig{
+	#[serde(default="default_format")]
+	pub format:LogFormat,
+	/** tracingのフィルタ構文。RUST_LOGが設定されている場合はそちらを使う*/
+	#[serde(default="default_level")]
+	pub level:String,
+	/** ログに書き出すURLからクエリ文字列を取り除く*/
+	#[serde(default)]
+	pub redact_query:bool,
+}
+fn default_format()->LogFormat{
+	LogFormat::Human
+}
+fn default_level()->String{
+	"info".to_owned()
+}
+impl Default for LogConfig{
+	fn default()->Self{
+		Self{
+			format:default_format(),
+			level:default_level(),
+			redact_query:false,
+		}
+	}
+}
+impl LogConfig{
+	pub fn init(&self){
+		use tracing_subscriber::fmt::format::FmtSpan;
+		let filter=tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_|tracing_subscriber::EnvFilter::new(&self.level));
+		//閉じた時にspanの所要時間を出力する
+		let builder=tracing_subscriber::fmt().with_env_filter(filter).with_span_events(FmtSpan::CLOSE);
+		match self.format{
+			LogFormat::Human=>builder.init(),
+			LogFormat::Json=>builder.json().init(),
+		}
+	}
+	/** ログに書き出すURL*/
+	pub fn url<'a>(&self,url:&'a str)->&'a str{
+		if self.redact_query{
+			match url.find(['?','#']){
+				Some(idx)=>&url[..idx],
+				None=>url,
+			}
+		}else{
+			url
+		}
+	}
+}
+/** ログでリクエストを区別するための連番*/
+pub fn next_request_id()->u64{
+	static REQUEST_ID:std::sync::atomic::AtomicU64=std::sync::atomic::AtomicU64::new(0);
+	REQUEST_ID.fetch_add(1,std::sync::atomic::Ordering::Relaxed)
+}
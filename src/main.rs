@@ -1,11 +1,40 @@
-use std::{borrow::Cow, collections::HashMap, io::Write, net::SocketAddr, sync::Arc};
+use std::{borrow::Cow, collections::HashMap, net::SocketAddr, sync::Arc};
 
 use axum::{response::IntoResponse, Router};
 use serde::{Deserialize, Serialize};
 use tokio_stream::StreamExt;
+use tracing::Instrument;
+
+mod auth;
+mod batch;
+mod canonical;
+mod cli;
+mod compression;
+mod config;
+mod explain;
+mod favicon;
+mod headers;
+mod health;
+mod icon;
+mod jsonld;
+mod listen;
+mod logging;
+mod manifest;
+mod metrics;
+mod player;
+mod subrequest;
+mod thumbnail;
 
 /** レートリミット対象の処理が終わった時に破棄する*/
 struct RateLimitTracker(Option<RateLimit>,Option<String>,tokio::runtime::Handle);
+impl RateLimitTracker{
+	/** 上流への接続結果をサーキットブレーカーとバックオフに記録する*/
+	async fn report(&self,success:bool,retry_after:Option<UpstreamRetryAfter>,config:&ConfigFile){
+		if let (Some(limit),Some(host))=(self.0.as_ref(),self.1.as_ref()){
+			limit.report(host,success,retry_after,config).await;
+		}
+	}
+}
 impl Drop for RateLimitTracker{
 	fn drop(&mut self) {
 		let s=self.1.take().unwrap();
@@ -20,15 +49,204 @@ impl Drop for RateLimitTracker{
 		});
 	}
 }
+/** ホスト毎のサーキットブレーカーの状態*/
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum CircuitState{
+	/** 連続した失敗の回数と最後に失敗した時刻*/
+	Closed(u32,std::time::Instant),
+	/** この時刻まで即時失敗する*/
+	Open(std::time::Instant),
+	/** 試行中のリクエストの開始時刻。結果が出るまで他は即時失敗する*/
+	HalfOpen(std::time::Instant),
+}
+impl std::fmt::Display for CircuitState{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self{
+			CircuitState::Closed(..)=>write!(f,"closed"),
+			CircuitState::Open(_)=>write!(f,"open"),
+			CircuitState::HalfOpen(_)=>write!(f,"half-open"),
+		}
+	}
+}
+#[derive(Clone,Copy,Debug)]
+struct TokenBucket{
+	tokens:f64,
+	updated:std::time::Instant,
+	/** 最後に使われた時のClientRateLimitConfig.rate。キー毎に異なる*/
+	rate:f64,
+	/** 最後に使われた時のClientRateLimitConfig.burst*/
+	burst:f64,
+}
+impl TokenBucket{
+	/** 経過時間分を回復した現在のトークン数*/
+	fn tokens(&self,now:std::time::Instant)->f64{
+		let elapsed=now.saturating_duration_since(self.updated).as_secs_f64();
+		(self.tokens+elapsed*self.rate).min(self.burst)
+	}
+}
+/** 上流から待つように指示されたホスト*/
+#[derive(Clone,Copy,Debug)]
+struct Backoff{
+	/** この時刻まで接続しない*/
+	until:std::time::Instant,
+	/** 連続して待つように指示された回数*/
+	count:u32,
+}
+#[derive(Debug)]
+enum RateLimitError{
+	/** 待てば成功する可能性がある*/
+	Retry,
+	/** 対象のURLが不正*/
+	InvalidUrl,
+	/** サーキットブレーカーが開いている*/
+	CircuitOpen(CircuitState,std::time::Duration),
+	/** 上流から待つように指示されている*/
+	Backoff(std::time::Duration),
+}
 #[derive(Clone,Debug)]
 struct RateLimit{
 	hosts:Arc<tokio::sync::RwLock<HashMap<String,u32>>>,
+	circuits:Arc<tokio::sync::RwLock<HashMap<String,CircuitState>>>,
+	backoffs:Arc<tokio::sync::RwLock<HashMap<String,Backoff>>>,
+	clients:Arc<tokio::sync::Mutex<HashMap<String,TokenBucket>>>,
 }
 impl RateLimit{
-	/** 処理を実行しても良いか確認し、ロックを取得する。Err(true)は待てば成功する可能性がある*/
-	async fn request(&self,url:&str)->Result<RateLimitTracker,bool>{
-		let host=reqwest::Url::parse(url).map_err(|_|false)?;
-		let host=host.host().ok_or(false)?.to_string();
+	/** クライアント毎のトークンを1つ消費する。Errは次のトークンが貯まるまでの時間*/
+	async fn client(&self,key:String,config:&ClientRateLimitConfig)->Result<(),std::time::Duration>{
+		let now=std::time::Instant::now();
+		let mut lock=self.clients.lock().await;
+		if lock.len()>=4096{
+			//満タンまで回復したものは消しても同じ
+			lock.retain(|_,bucket|bucket.tokens(now)<bucket.burst);
+			if lock.len()>=4096{
+				//回復中のものしか無い場合は古いものから3/4まで減らす
+				let mut updated:Vec<std::time::Instant>=lock.values().map(|bucket|bucket.updated).collect();
+				updated.sort_unstable();
+				let threshold=updated[lock.len()-3072];
+				lock.retain(|_,bucket|bucket.updated>=threshold);
+			}
+		}
+		let bucket=lock.entry(key).or_insert(TokenBucket{
+			tokens:config.burst as f64,
+			updated:now,
+			rate:config.rate,
+			burst:config.burst as f64,
+		});
+		//設定が読み直された場合は新しい値で回復する
+		bucket.rate=config.rate;
+		bucket.burst=config.burst as f64;
+		let tokens=bucket.tokens(now);
+		bucket.updated=now;
+		if tokens>=1.0{
+			bucket.tokens=tokens-1.0;
+			Ok(())
+		}else{
+			bucket.tokens=tokens;
+			//rateが0の場合は回復しない
+			Err(std::time::Duration::try_from_secs_f64((1.0-tokens)/config.rate).unwrap_or(std::time::Duration::from_secs(3600)))
+		}
+	}
+	/** 上流への接続結果をサーキットブレーカーとバックオフに記録する*/
+	async fn report(&self,host:&str,success:bool,retry_after:Option<UpstreamRetryAfter>,config:&ConfigFile){
+		let mut wlock=self.backoffs.write().await;
+		if let Some(UpstreamRetryAfter(retry_after))=retry_after{
+			let count=wlock.get(host).map(|b|b.count).unwrap_or(0);
+			let max=std::time::Duration::from_millis(config.backoff_max);
+			let delay=match retry_after{
+				Some(retry_after)=>retry_after,
+				None=>std::time::Duration::from_millis(config.backoff_base).saturating_mul(1<<count.min(16)),
+			}.min(max);
+			tracing::warn!(host=%host,delay_ms=delay.as_millis() as u64,"backoff");
+			let now=std::time::Instant::now();
+			//期限が過ぎてもbackoff_maxの間は回数を覚えておく。それより古いものは他のホストの分も消す
+			wlock.retain(|_,backoff|backoff.until+max>now);
+			wlock.insert(host.to_owned(),Backoff{
+				until:now+delay,
+				count:count+1,
+			});
+		}else if success&&wlock.get(host).map(|b|b.until<=std::time::Instant::now()).unwrap_or(false){
+			//待った後に成功したので次は最初の待ち時間から
+			wlock.remove(host);
+		}
+		drop(wlock);
+		if config.circuit_breaker_threshold==0{
+			return;
+		}
+		let mut wlock=self.circuits.write().await;
+		let state=wlock.get(host).copied();
+		if success{
+			wlock.remove(host);
+			if let Some(state@(CircuitState::Open(_)|CircuitState::HalfOpen(_)))=state{
+				tracing::info!(host=%host,previous=%state,"circuit closed");
+			}
+			return;
+		}
+		let now=std::time::Instant::now();
+		let cooldown=std::time::Duration::from_millis(config.circuit_breaker_cooldown);
+		if wlock.len()>=4096{
+			//クールダウンより前の失敗は連続とみなさないので消しても同じ
+			wlock.retain(|_,state|match state{
+				CircuitState::Closed(_,last)=>*last+cooldown>now,
+				CircuitState::Open(until)=>*until>now,
+				CircuitState::HalfOpen(since)=>*since+cooldown>now,
+			});
+			if wlock.len()>=4096{
+				wlock.retain(|_,state|!matches!(state,CircuitState::Closed(..)));
+			}
+		}
+		//クールダウンより前の失敗は連続とみなさない
+		let state=state.filter(|state|!matches!(state,CircuitState::Closed(_,last) if *last+cooldown<=now));
+		let next=match state{
+			Some(CircuitState::HalfOpen(_))=>{
+				tracing::warn!(host=%host,"circuit open (half-open probe failed)");
+				CircuitState::Open(now+cooldown)
+			},
+			//開いている間に完了した処理の失敗
+			Some(CircuitState::Open(until))=>CircuitState::Open(until),
+			Some(CircuitState::Closed(failures,_)) if failures+1<config.circuit_breaker_threshold=>{
+				CircuitState::Closed(failures+1,now)
+			},
+			None if 1<config.circuit_breaker_threshold=>CircuitState::Closed(1,now),
+			Some(CircuitState::Closed(failures,_))=>{
+				tracing::warn!(host=%host,failures=failures+1,"circuit open");
+				CircuitState::Open(now+cooldown)
+			},
+			None=>{
+				tracing::warn!(host=%host,failures=1,"circuit open");
+				CircuitState::Open(now+cooldown)
+			},
+		};
+		wlock.insert(host.to_owned(),next);
+	}
+	/** サーキットブレーカーとバックオフだけを確認する。枠は取得しない*/
+	async fn check(&self,host:&str,config:&ConfigFile)->Result<(),RateLimitError>{
+		let cooldown=std::time::Duration::from_millis(config.circuit_breaker_cooldown);
+		let now=std::time::Instant::now();
+		let rlock=self.circuits.read().await;
+		match rlock.get(host).copied(){
+			Some(state@CircuitState::Open(until)) if until>now=>{
+				return Err(RateLimitError::CircuitOpen(state,until-now));
+			},
+			Some(state@CircuitState::HalfOpen(since)) if since+cooldown>now=>{
+				return Err(RateLimitError::CircuitOpen(state,since+cooldown-now));
+			},
+			_=>{}
+		}
+		drop(rlock);
+		let rlock=self.backoffs.read().await;
+		if let Some(backoff)=rlock.get(host){
+			if backoff.until>now{
+				return Err(RateLimitError::Backoff(backoff.until-now));
+			}
+		}
+		Ok(())
+	}
+	/** 処理を実行しても良いか確認し、ロックを取得する*/
+	async fn request(&self,url:&str,config:&ConfigFile)->Result<RateLimitTracker,RateLimitError>{
+		let host=host_key(url).ok_or(RateLimitError::InvalidUrl)?;
+		self.check(&host,config).await?;
+		let cooldown=std::time::Duration::from_millis(config.circuit_breaker_cooldown);
+		let now=std::time::Instant::now();
 		let rlock=self.hosts.read().await;
 		let active_tasks=rlock.get(&host).copied().unwrap_or(0);
 		drop(rlock);
@@ -37,40 +255,210 @@ impl RateLimit{
 			//処理中がこれ含め3件未満であれば即時実行
 			let mut wlock=self.hosts.write().await;
 			wlock.insert(host.clone(),active_tasks);
+			drop(wlock);
 			let handle=tokio::runtime::Handle::current();
-			Ok(RateLimitTracker(Some(self.clone()),Some(host),handle))
+			//以降で失敗した場合はdropで枠を返す
+			let tracker=RateLimitTracker(Some(self.clone()),Some(host.clone()),handle);
+			let mut wlock=self.circuits.write().await;
+			match wlock.get(&host).copied(){
+				Some(state@CircuitState::HalfOpen(since)) if since+cooldown>now=>{
+					//他のリクエストが先に試行を始めた
+					return Err(RateLimitError::CircuitOpen(state,since+cooldown-now));
+				},
+				Some(CircuitState::Open(_))|Some(CircuitState::HalfOpen(_))=>{
+					//クールダウンが経過したので試行を1件だけ通す
+					tracing::info!(host=%host,"circuit half-open");
+					wlock.insert(host.clone(),CircuitState::HalfOpen(now));
+				},
+				_=>{}
+			}
+			drop(wlock);
+			Ok(tracker)
 		}else{
 			//再試行するべき失敗
-			Err(true)
+			Err(RateLimitError::Retry)
 		}
 	}
 }
+/** ホスト毎の制限で使うキー*/
+fn host_key(url:&str)->Option<String>{
+	Some(reqwest::Url::parse(url).ok()?.host()?.to_string())
+}
+/** 上流に接続できなかった事をサーキットブレーカーに伝える*/
+#[derive(Clone,Copy,Debug)]
+struct UpstreamFailure;
+/** 上流が429/503を返した。Retry-Afterが無い場合は指数バックオフする*/
+#[derive(Clone,Copy,Debug)]
+struct UpstreamRetryAfter(Option<std::time::Duration>);
+/** Retry-Afterヘッダの秒数またはHTTP-dateを解釈する*/
+fn parse_retry_after(value:&str)->Option<std::time::Duration>{
+	let value=value.trim();
+	if let Ok(secs)=value.parse::<u64>(){
+		return Some(std::time::Duration::from_secs(secs));
+	}
+	let date=chrono::DateTime::parse_from_rfc2822(value).ok()?;
+	let delta=date.with_timezone(&chrono::Utc)-chrono::Utc::now();
+	Some(delta.to_std().unwrap_or(std::time::Duration::ZERO))
+}
 #[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default,deny_unknown_fields)]
 pub struct ConfigFile{
-	bind_addr: String,
+	bind_addr:listen::BindAddr,
+	/** Unixドメインソケットのパーミッション(8進数)。例: "660"*/
+	unix_socket_mode:Option<String>,
 	timeout:u64,
 	user_agent:String,
 	max_size:u32,
 	proxy:Option<String>,
 	media_proxy:Option<String>,
+	/** 全ての応答に付ける"Name:value"形式のヘッダ。headersより優先される*/
 	append_headers:Vec<String>,
+	headers:headers::HeadersConfig,
+	compression:compression::CompressionConfig,
+	/** 連続してこの回数失敗したホストへの接続を止める。0で無効*/
+	circuit_breaker_threshold:u32,
+	/** 止めたホストへ再試行するまでの時間(ms)*/
+	circuit_breaker_cooldown:u64,
+	/** 上流が429/503をRetry-After無しで返した時に待つ時間(ms)。連続する毎に倍になる*/
+	backoff_base:u64,
+	/** 上流から指示された待ち時間の上限(ms)*/
+	backoff_max:u64,
+	/** クライアントのIPアドレス毎の流量制限。nullで無効*/
+	client_rate_limit:Option<ClientRateLimitConfig>,
+	/** APIキーまたは署名付きURLを要求する。nullで誰でも使える*/
+	auth:Option<auth::AuthConfig>,
+	/** Prometheus形式の統計を公開する。nullで無効*/
+	metrics:Option<metrics::MetricsConfig>,
+	log:logging::LogConfig,
+	/** /readyzで組み込みのHTMLを要約できるか確認する*/
+	readiness_self_test:bool,
+	/** アイコンが無い場合に/favicon.icoが存在するか確かめて使う。nullで/favicon.icoを使わない*/
+	favicon_probe:Option<favicon::FaviconProbeConfig>,
+	/** サムネイルの寸法等を調べる。nullで調べない*/
+	thumbnail_probe:Option<thumbnail::ThumbnailProbeConfig>,
+	player:player::PlayerConfig,
+	/** rel=canonicalやog:urlの扱い*/
+	canonical_url:canonical::CanonicalPolicy,
+	/** アイコンを選ぶ時に目標とする大きさ(px)*/
+	icon_size:u32,
+	/** headにサイト名や十分な大きさのアイコンが無い場合にWeb App Manifestを取得して補う*/
+	web_app_manifest:bool,
+	/** POST /batchで一度に受け付けるURLの数*/
+	batch_max_size:usize,
+	/** POST /batch全体の締め切り(ms)*/
+	batch_timeout:u64,
 }
-impl ConfigFile{
-	fn append_headers(&self,headers:&mut axum::http::HeaderMap){
-		use std::str::FromStr;
-		for line in self.append_headers.iter(){
-			if let Some(idx)=line.find(":"){
-				if idx+1>=line.len(){
-					continue;
-				}
-				if let Ok(k)=axum::http::HeaderName::from_str(&line[0..idx]){
-					if let Ok(v)=line[idx+1..].parse(){
-						headers.append(k,v);
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientRateLimitConfig{
+	/** 1秒あたりに回復するリクエスト数*/
+	rate:f64,
+	/** 連続して受け付けるリクエスト数*/
+	burst:u32,
+	/** X-Forwarded-Forを信用する接続元のCIDR*/
+	#[serde(default)]
+	trusted_proxies:Vec<ipnet::IpNet>,
+}
+impl ClientRateLimitConfig{
+	/** 信用するプロキシを辿ってクライアントのアドレスを求める*/
+	fn client_ip(&self,peer:std::net::IpAddr,headers:&axum::http::HeaderMap)->std::net::IpAddr{
+		let is_trusted=|ip:&std::net::IpAddr|self.trusted_proxies.iter().any(|net|net.contains(ip));
+		if !is_trusted(&peer){
+			return peer;
+		}
+		let mut client=peer;
+		let forwarded=headers.get_all("X-Forwarded-For").iter().filter_map(|v|v.to_str().ok());
+		let hops:Vec<&str>=forwarded.flat_map(|v|v.split(',')).map(|v|v.trim()).collect();
+		//右側が自分に近い
+		for hop in hops.iter().rev(){
+			match hop.parse::<std::net::IpAddr>(){
+				Ok(ip)=>{
+					client=ip;
+					if !is_trusted(&ip){
+						break;
 					}
-				}
+				},
+				Err(_)=>break,
 			}
 		}
+		client
+	}
+	/** IPv6は1つの回線に/64が割り当てられるのでまとめて数える*/
+	fn client_key(&self,peer:std::net::IpAddr,headers:&axum::http::HeaderMap)->String{
+		match self.client_ip(peer,headers){
+			std::net::IpAddr::V6(ip) if ip.to_ipv4_mapped().is_none()=>{
+				ipnet::Ipv6Net::new(ip,64).map(|net|net.trunc().to_string()).unwrap_or_else(|_|ip.to_string())
+			},
+			ip=>ip.to_canonical().to_string(),
+		}
+	}
+}
+impl Default for ConfigFile{
+	fn default()->Self{
+		Self{
+			bind_addr:listen::BindAddr::One("0.0.0.0:12267".to_owned()),
+			unix_socket_mode:None,
+			timeout:5000,
+			user_agent: "https://github.com/yojo-art/summaly-rs".to_owned(),
+			max_size:2*1024*1024,
+			proxy:None,
+			media_proxy:None,//e.g. https://misskey.example.com/proxy/
+			append_headers:vec![],
+			headers:headers::HeadersConfig{
+				cors:Some(Default::default()),
+				content_security_policy:Some("default-src 'none'; img-src 'self'; media-src 'self'; style-src 'unsafe-inline'".to_owned()),
+				..Default::default()
+			},
+			compression:Default::default(),
+			circuit_breaker_threshold:5,
+			circuit_breaker_cooldown:30000,
+			backoff_base:1000,
+			backoff_max:10*60*1000,
+			client_rate_limit:None,
+			auth:None,
+			metrics:None,
+			log:Default::default(),
+			readiness_self_test:true,
+			favicon_probe:None,
+			thumbnail_probe:None,
+			player:Default::default(),
+			canonical_url:canonical::CanonicalPolicy::SameSite,
+			icon_size:64,
+			web_app_manifest:true,
+			batch_max_size:20,
+			batch_timeout:10000,
+		}
+	}
+}
+/** 上流への取得に使うクライアント。proxyは起動時にしか読まない*/
+fn http_client(config:&ConfigFile)->reqwest::Client{
+	let mut client=reqwest::ClientBuilder::new().redirect(explain::redirect_policy());
+	if let Some(proxy)=config.proxy.as_ref().and_then(|proxy|reqwest::Proxy::all(proxy).ok()){
+		client=client.proxy(proxy);
+	}
+	client.build().unwrap()
+}
+impl ConfigFile{
+	fn append_headers(&self,headers:&mut axum::http::HeaderMap){
+		let lines:Vec<_>=self.append_headers.iter().filter_map(|line|parse_header_line(line).ok()).collect();
+		for (k,_) in lines.iter(){
+			headers.remove(k);
+		}
+		for (k,v) in lines{
+			headers.append(k,v);
+		}
+	}
+}
+/** "Name:value"形式のヘッダを解釈する*/
+fn parse_header_line(line:&str)->Result<(axum::http::HeaderName,axum::http::HeaderValue),String>{
+	use std::str::FromStr;
+	let idx=line.find(":").ok_or("missing ':'")?;
+	if idx+1>=line.len(){
+		return Err("empty value".to_owned());
 	}
+	let k=axum::http::HeaderName::from_str(&line[0..idx]).map_err(|e|e.to_string())?;
+	let v=line[idx+1..].parse().map_err(|e:axum::http::header::InvalidHeaderValue|e.to_string())?;
+	Ok((k,v))
 }
 #[derive(Debug, Deserialize)]
 pub struct RequestParams{
@@ -82,10 +470,19 @@ pub struct RequestParams{
 	response_timeout:Option<u32>,
 	#[serde(rename = "contentLengthLimit")]
 	content_length_limit:Option<u32>,
+	/** Authorizationヘッダを使えない場合のAPIキー*/
+	key:Option<String>,
+	/** 署名付きURLの有効期限(UNIX時間秒)*/
+	expires:Option<i64>,
+	/** 署名付きURLの署名*/
+	signature:Option<String>,
+	/** 1の場合は各フィールドの出どころを_explainに含める*/
+	debug:Option<u8>,
 }
 #[derive(Debug,Serialize,Deserialize)]
 pub struct SummalyPlayer{
 	url:Option<String>,
+	r#type:Option<player::PlayerType>,
 	width:Option<f64>,
 	height:Option<f64>,
 	allow:Vec<String>,
@@ -103,6 +500,58 @@ pub struct SummalyResult{
 	#[serde(rename = "activityPub")]
 	activity_pub:Option<String>,
 	oembed:Option<OEmbed>,
+	article:Option<Article>,
+	#[serde(rename = "themeColor")]
+	theme_color:Option<String>,
+	#[serde(rename = "canonicalUrl")]
+	canonical_url:Option<String>,
+	#[serde(rename = "thumbnailInfo")]
+	thumbnail_info:Option<thumbnail::ImageInfo>,
+	images:Vec<thumbnail::Image>,
+	#[serde(rename = "_explain",skip_serializing_if = "Option::is_none",skip_deserializing)]
+	explain:Option<explain::Explain>,
+}
+#[derive(Debug,Default,Serialize,Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Article{
+	/** og:type*/
+	r#type:Option<String>,
+	headline:Option<String>,
+	author:Option<String>,
+	/** RFC 3339*/
+	published_time:Option<String>,
+	/** RFC 3339*/
+	modified_time:Option<String>,
+	section:Option<String>,
+	tags:Vec<String>,
+	keywords:Vec<String>,
+}
+impl Article{
+	fn is_empty(&self)->bool{
+		self.r#type.is_none()&&self.headline.is_none()&&self.author.is_none()&&self.published_time.is_none()&&self.modified_time.is_none()&&self.section.is_none()&&self.tags.is_empty()&&self.keywords.is_empty()
+	}
+}
+/** 日時の表記揺れをRFC 3339に揃える。タイムゾーンが無い場合はUTCとみなす*/
+fn normalize_time(value:&str)->Option<String>{
+	let value=value.trim();
+	if let Ok(date)=chrono::DateTime::parse_from_rfc3339(value){
+		return Some(date.to_rfc3339());
+	}
+	for format in ["%Y-%m-%dT%H:%M:%S%.f%z","%Y-%m-%d %H:%M:%S%.f%z"]{
+		if let Ok(date)=chrono::DateTime::parse_from_str(value,format){
+			return Some(date.to_rfc3339());
+		}
+	}
+	if let Ok(date)=chrono::DateTime::parse_from_rfc2822(value){
+		return Some(date.to_rfc3339());
+	}
+	for format in ["%Y-%m-%dT%H:%M:%S%.f","%Y-%m-%d %H:%M:%S%.f","%Y-%m-%dT%H:%M"]{
+		if let Ok(date)=chrono::NaiveDateTime::parse_from_str(value,format){
+			return Some(date.and_utc().to_rfc3339());
+		}
+	}
+	let date=chrono::NaiveDate::parse_from_str(value,"%Y-%m-%d").ok()?;
+	Some(date.and_hms_opt(0,0,0)?.and_utc().to_rfc3339())
 }
 #[derive(Debug,Serialize,Deserialize)]
 pub struct OEmbed{
@@ -122,6 +571,10 @@ pub struct OEmbed{
 	width:Option<f64>,
 	height:Option<f64>,
 }
+/** リクエスト毎にその時点の設定を読み込む*/
+fn load_args((client,config,limit):&(reqwest::Client,config::SharedConfig,RateLimit))->(reqwest::Client,Arc<ConfigFile>,RateLimit){
+	(client.clone(),config.load(),limit.clone())
+}
 async fn shutdown_signal() {
 	use tokio::signal;
 	use futures::{future::FutureExt,pin_mut};
@@ -147,104 +600,204 @@ async fn shutdown_signal() {
 	}
 }
 fn main() {
-	let config_path=match std::env::var("SUMMALY_CONFIG_PATH"){
-		Ok(path)=>{
-			if path.is_empty(){
-				"config.json".to_owned()
-			}else{
-				path
+	//環境変数はスレッドを起動する前に読んで消す
+	let systemd_fds=listen::take_systemd_fds();
+	let args:Vec<String>=std::env::args().collect();
+	let (config_path,args)=config::config_path(args);
+	match args.get(1).map(|s|s.as_str()){
+		Some("--init-config")=>std::process::exit(config::init_config(&config_path)),
+		Some("--check-config")=>std::process::exit(config::check_config(&config_path)),
+		_=>{}
+	}
+	let config=match config::load(&config_path){
+		Ok(config)=>config,
+		Err(errors)=>{
+			for e in errors{
+				eprintln!("{}: {}",config_path,e);
 			}
-		},
-		Err(_)=>"config.json".to_owned()
+			std::process::exit(1);
+		}
 	};
-	if !std::path::Path::new(&config_path).exists(){
-		let default_config=ConfigFile{
-			bind_addr: "0.0.0.0:12267".to_owned(),
-			timeout:5000,
-			user_agent: "https://github.com/yojo-art/summaly-rs".to_owned(),
-			max_size:2*1024*1024,
-			proxy:None,
-			media_proxy:None,//e.g. https://misskey.example.com/proxy/
-			append_headers:[
-				"Content-Security-Policy:default-src 'none'; img-src 'self'; media-src 'self'; style-src 'unsafe-inline'".to_owned(),
-				"Access-Control-Allow-Origin:*".to_owned(),
-			].to_vec(),
-		};
-		let default_config=serde_json::to_string_pretty(&default_config).unwrap();
-		std::fs::File::create(&config_path).expect("create default config.json").write_all(default_config.as_bytes()).unwrap();
+	match args.get(1).map(|s|s.as_str()){
+		Some("--healthcheck")=>std::process::exit(health::healthcheck(&config)),
+		Some("fetch")=>std::process::exit(cli::fetch(&args[2..],config)),
+		_=>{}
 	}
-	let config:ConfigFile=serde_json::from_reader(std::fs::File::open(&config_path).unwrap()).unwrap();
+	config.log.init();
 	let config=Arc::new(config);
-	let client=reqwest::ClientBuilder::new();
-	let client=client.build().unwrap();
+	let client=http_client(&config);
 	let rt=tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
 	let limit=RateLimit{
-		hosts:Arc::new(tokio::sync::RwLock::new(HashMap::new()))
+		hosts:Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+		circuits:Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+		backoffs:Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+		clients:Arc::new(tokio::sync::Mutex::new(HashMap::new())),
 	};
-	let arg_tup=(client,config,limit);
+	let metrics_config=config.metrics.clone();
+	let compression_config=config.compression.clone();
+	let arg_tup=(client,config::SharedConfig::new(config),limit);
 	rt.block_on(async{
-		let http_addr:SocketAddr = arg_tup.1.bind_addr.parse().unwrap();
+		let listeners=match listen::bind(&arg_tup.1.load(),systemd_fds).await{
+			Ok(listeners)=>listeners,
+			Err(e)=>{
+				tracing::error!(error=%e,"bind");
+				std::process::exit(1);
+			}
+		};
+		tokio::spawn(config::reload_on_sighup(config_path,arg_tup.1.clone()));
 		let app = Router::new();
 		let arg_tup0=arg_tup.clone();
-		let app=app.route("/",axum::routing::get(move|headers,parms|get_file(None,headers,arg_tup0.clone(),parms)));
-		let app=app.route("/*path",axum::routing::get(move|path,headers,parms|get_file(Some(path),headers,arg_tup.clone(),parms)));
-		let comression_layer= tower_http::compression::CompressionLayer::new()
-		.gzip(true);
-		let app=app.layer(comression_layer);
-		let listener = tokio::net::TcpListener::bind(&http_addr).await.unwrap();
-		axum::serve(listener,app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(shutdown_signal()).await.unwrap();
+		let (arg_tup1,arg_tup2)=(arg_tup.clone(),arg_tup.clone());
+		let limit=arg_tup.2.clone();
+		let (health_client,health_config)=(arg_tup.0.clone(),arg_tup.1.clone());
+		let headers_config=arg_tup.1.clone();
+		let app=app.route("/",axum::routing::get(move|connect_info,headers,parms|get_file(None,connect_info,headers,load_args(&arg_tup0),parms)));
+		let app=app.route("/*path",axum::routing::get(move|path,connect_info,headers,parms|get_file(Some(path),connect_info,headers,load_args(&arg_tup),parms)));
+		//GET /batchは今まで通り/*pathと同じ扱い
+		let app=app.route("/batch",axum::routing::post(move|connect_info,headers,bq,items|batch::post_batch(connect_info,headers,load_args(&arg_tup1),bq,items))
+			.get(move|connect_info,headers,parms|get_file(None,connect_info,headers,load_args(&arg_tup2),parms)));
+		let app=app.layer(axum::middleware::from_fn(metrics::record_request));
+		//CORSやセキュリティのヘッダは/healthz等を含む全ての応答に付ける
+		let headers_layer=axum::middleware::from_fn(move|req,next|headers::apply_headers(headers_config.clone(),req,next));
+		let app=match metrics_config{
+			Some(metrics::MetricsConfig{bind_addr:Some(metrics_addr),path})=>{
+				let metrics_addr:SocketAddr=match metrics_addr.parse(){
+					Ok(addr)=>addr,
+					Err(e)=>{
+						tracing::error!(error=%e,addr=%metrics_addr,"metrics.bind_addr");
+						std::process::exit(1);
+					}
+				};
+				let metrics_app=Router::new().route(&path,axum::routing::get(move||metrics::get_metrics(limit.clone())));
+				let metrics_app=metrics_app.layer(headers_layer.clone());
+				let listener=match tokio::net::TcpListener::bind(&metrics_addr).await{
+					Ok(listener)=>listener,
+					Err(e)=>{
+						tracing::error!(error=%e,addr=%metrics_addr,"metrics bind");
+						std::process::exit(1);
+					}
+				};
+				tokio::spawn(async move{
+					if let Err(e)=axum::serve(listener,metrics_app).await{
+						tracing::error!(error=%e,"metrics serve");
+					}
+				});
+				app
+			},
+			Some(metrics::MetricsConfig{bind_addr:None,path})=>{
+				//要約のルートより優先される
+				app.route(&path,axum::routing::get(move||metrics::get_metrics(limit.clone())))
+			},
+			None=>app,
+		};
+		let app=app.route("/healthz",axum::routing::get(health::healthz));
+		let (client,config)=(health_client,health_config);
+		let app=app.route("/readyz",axum::routing::get(move||health::readyz(client.clone(),config.load())));
+		let app=app.layer(headers_layer);
+		let app=app.layer(compression_config.layer());
+		listen::serve(listeners,app,shutdown_signal()).await;
 	});
 }
+#[tracing::instrument(name="request",skip_all,fields(id=logging::next_request_id(),host=tracing::field::Empty))]
 async fn get_file(
 	_path:Option<axum::extract::Path<String>>,
+	axum::extract::ConnectInfo(peer):axum::extract::ConnectInfo<SocketAddr>,
 	request_headers:axum::http::HeaderMap,
 	(client,config,limit):(reqwest::Client,Arc<ConfigFile>,RateLimit),
 	axum::extract::Query(q):axum::extract::Query<RequestParams>,
 )->axum::response::Response{
-	println!("{}\t{}\tlang:{:?}\tresponse_timeout:{:?}\tcontent_length_limit:{:?}\tuser_agent:{:?}",
-		chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
-		q.url,
-		q.lang,
-		q.user_agent,
-		q.response_timeout,
-		q.content_length_limit,
+	if let Some(host)=reqwest::Url::parse(&q.url).ok().as_ref().and_then(|u|u.host_str()){
+		tracing::Span::current().record("host",host);
+	}
+	tracing::info!(
+		url=config.log.url(&q.url),
+		lang=?q.lang,
+		user_agent=?q.user_agent,
+		response_timeout=?q.response_timeout,
+		content_length_limit=?q.content_length_limit,
+		"summary",
 	);
+	let authorized=match auth::authorize(&config,&request_headers,&q){
+		Ok(authorized)=>authorized,
+		Err(e)=>{
+			let mut headers=axum::http::HeaderMap::new();
+			headers.append("X-Proxy-Error",e.parse().unwrap());
+			headers.append(axum::http::header::WWW_AUTHENTICATE,"Bearer".parse().unwrap());
+			return (axum::http::StatusCode::UNAUTHORIZED,headers).into_response();
+		}
+	};
+	let client_limit=match authorized{
+		//キー毎の制限があればIPアドレスの制限の代わりに使う
+		auth::Authorized::Key(key) if key.rate_limit.is_some()=>{
+			key.rate_limit.as_ref().map(|rate_limit|(format!("key:{}",key.name),rate_limit))
+		},
+		_=>config.client_rate_limit.as_ref().map(|rate_limit|{
+			(rate_limit.client_key(peer.ip(),&request_headers),rate_limit)
+		}),
+	};
+	if let Some((client_key,rate_limit))=client_limit{
+		if let Err(retry_after)=limit.client(client_key,rate_limit).await{
+			let mut headers=axum::http::HeaderMap::new();
+			headers.append("X-Proxy-Error","client rate limit".parse().unwrap());
+			headers.append(axum::http::header::RETRY_AFTER,retry_after.as_secs_f64().ceil().max(1.0).to_string().parse().unwrap());
+			return (axum::http::StatusCode::TOO_MANY_REQUESTS,headers).into_response();
+		}
+	}
 	if q.url.starts_with("coffee://"){
 		let mut headers=axum::http::HeaderMap::new();
 		headers.append("X-Proxy-Error","I'm a teapot".parse().unwrap());
-		config.append_headers(&mut headers);
 		return (axum::http::StatusCode::IM_A_TEAPOT,headers).into_response()
 	}
 	for _ in 0..3{
-		match limit.request(&q.url).await{
-			Ok(_)=>{
-				return remote_request(request_headers,(client,config),q).await;
+		match limit.request(&q.url,&config).await{
+			Ok(tracker)=>{
+				let res=remote_request(request_headers,(client,config.clone()),Some(&limit),q).await;
+				let success=res.extensions().get::<UpstreamFailure>().is_none();
+				let retry_after=res.extensions().get::<UpstreamRetryAfter>().copied();
+				tracker.report(success,retry_after,&config).await;
+				return res;
 			},
-			Err(true)=>{
+			Err(RateLimitError::Retry)=>{
 				tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
 			},
-			_=>{
+			Err(RateLimitError::Backoff(retry_after)) if retry_after<=std::time::Duration::from_millis(1000)=>{
+				//すぐに解除されるので待つ
+				tokio::time::sleep(retry_after).await;
+			},
+			Err(RateLimitError::Backoff(retry_after))=>{
+				let mut headers=axum::http::HeaderMap::new();
+				headers.append("X-Proxy-Error","upstream backoff".parse().unwrap());
+				headers.append(axum::http::header::RETRY_AFTER,retry_after.as_secs().max(1).to_string().parse().unwrap());
+				return (axum::http::StatusCode::TOO_MANY_REQUESTS,headers).into_response();
+			},
+			Err(RateLimitError::CircuitOpen(state,retry_after))=>{
+				let mut headers=axum::http::HeaderMap::new();
+				headers.append("X-Proxy-Error","circuit open".parse().unwrap());
+				headers.append("X-Circuit-State",state.to_string().parse().unwrap());
+				headers.append(axum::http::header::RETRY_AFTER,retry_after.as_secs().max(1).to_string().parse().unwrap());
+				return (axum::http::StatusCode::SERVICE_UNAVAILABLE,headers).into_response();
+			},
+			Err(RateLimitError::InvalidUrl)=>{
 				let mut headers=axum::http::HeaderMap::new();
 				headers.append(axum::http::header::CACHE_CONTROL,"public, max-age=30".parse().unwrap());
-				config.append_headers(&mut headers);
 				return (axum::http::StatusCode::BAD_REQUEST,headers).into_response();
 			}
 		}
 	}
 	let mut headers=axum::http::HeaderMap::new();
 	headers.append(axum::http::header::CACHE_CONTROL,"public, max-age=30".parse().unwrap());
-	config.append_headers(&mut headers);
 	(axum::http::StatusCode::TOO_MANY_REQUESTS,headers).into_response()
 }
 async fn remote_request(
 		request_headers:axum::http::HeaderMap,
 		(client,config):(reqwest::Client,Arc<ConfigFile>),
-		q:RequestParams,
+		limit:Option<&RateLimit>,
+		mut q:RequestParams,
 	)->axum::response::Response{
 	let builder=client.get(&q.url);
 	let user_agent=q.user_agent.as_ref().unwrap_or_else(||&config.user_agent);
 	let builder=builder.header(reqwest::header::USER_AGENT,user_agent);
-	let builder=if let Some(lang)=q.lang{
+	let builder=if let Some(lang)=&q.lang{
 		builder.header(reqwest::header::ACCEPT_LANGUAGE,lang)
 	}else{
 		builder
@@ -252,25 +805,91 @@ async fn remote_request(
 	let timeout_ms=config.timeout.min(q.response_timeout.unwrap_or(u32::MAX) as u64);
 	let builder=builder.timeout(std::time::Duration::from_millis(timeout_ms));
 	let content_length_limit=q.content_length_limit.unwrap_or(config.max_size);
-	let resp=builder.send().await;
+	//クライアントが短くしたタイムアウトによる失敗は上流の障害とみなさない
+	let client_timeout=timeout_ms<config.timeout;
+	let fetch_start=std::time::Instant::now();
+	//oEmbed等の付随する取得もページと同じ締め切りまでに終える
+	let deadline=tokio::time::Instant::now()+std::time::Duration::from_millis(timeout_ms);
+	//リダイレクト前のURLでページの枠を取っている
+	let page_host=host_key(&q.url);
+	let fetch_span=tracing::info_span!("fetch");
+	let mut explain=explain::Explain::new(q.debug.unwrap_or(0)!=0);
+	let (resp,redirects)=explain::with_redirects(builder.send().instrument(fetch_span.clone())).await;
+	explain.redirects(redirects);
 	let resp=match resp{
 		Ok(resp)=>resp,
 		Err(e)=>{
 			let mut headers=axum::http::HeaderMap::new();
 			headers.append("X-Proxy-Error",e.to_string().parse().unwrap());
-			config.append_headers(&mut headers);
-			return (axum::http::StatusCode::INTERNAL_SERVER_ERROR,headers).into_response()
+			let mut res=(axum::http::StatusCode::INTERNAL_SERVER_ERROR,headers).into_response();
+			if !(client_timeout&&e.is_timeout()){
+				res.extensions_mut().insert(UpstreamFailure);
+			}
+			return res
 		},
 	};
-	let v=match load_all(resp,content_length_limit.into()).await{
-		Ok(v)=>v,
+	if resp.status()==reqwest::StatusCode::TOO_MANY_REQUESTS||resp.status()==reqwest::StatusCode::SERVICE_UNAVAILABLE{
+		let retry_after=resp.headers().get(reqwest::header::RETRY_AFTER).and_then(|v|v.to_str().ok()).and_then(parse_retry_after);
+		let mut headers=axum::http::HeaderMap::new();
+		headers.append("X-Proxy-Error",format!("upstream {}",resp.status().as_u16()).parse().unwrap());
+		if let Some(retry_after)=retry_after{
+			headers.append(axum::http::header::RETRY_AFTER,retry_after.as_secs().min(config.backoff_max/1000).max(1).to_string().parse().unwrap());
+		}
+		let mut res=(axum::http::StatusCode::TOO_MANY_REQUESTS,headers).into_response();
+		res.extensions_mut().insert(UpstreamRetryAfter(retry_after));
+		return res
+	}
+	//リダイレクトした場合は最終的に取得したURLを基準にする
+	q.url=resp.url().to_string();
+	let v=match load_all(resp,content_length_limit.into()).instrument(fetch_span).await{
+		Ok(v)=>{
+			metrics::METRICS.fetch(fetch_start.elapsed());
+			explain.timing("fetch",fetch_start.elapsed());
+			v
+		},
 		Err(e)=>{
 			let mut headers=axum::http::HeaderMap::new();
 			headers.append("X-Proxy-Error",e.parse().unwrap());
-			config.append_headers(&mut headers);
-			return (axum::http::StatusCode::INTERNAL_SERVER_ERROR,headers).into_response()
+			let mut res=(axum::http::StatusCode::INTERNAL_SERVER_ERROR,headers).into_response();
+			//サイズ超過はcontentLengthLimitに依存するので数えない
+			if e.starts_with("LoadAll:")||(!client_timeout&&e.starts_with("Timeout:")){
+				//本文の受信中に切断された・タイムアウトした
+				res.extensions_mut().insert(UpstreamFailure);
+			}
+			return res
 		},
 	};
+	let sub=subrequest::Subrequest::new(&client,limit,page_host.as_deref(),&config,&q,deadline);
+	match summarize(&v,&q,&sub,explain).await{
+		Ok(resp)=>{
+			if let Ok(json)=serde_json::to_string(&resp){
+				let mut headers=axum::http::HeaderMap::new();
+				headers.append(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+				//debug=1の応答は_explainを含むので共有キャッシュに入れない
+				let cache_control=if q.debug.unwrap_or(0)!=0{
+					"no-store"
+				}else{
+					"public, max-age=1800"
+				};
+				headers.append(axum::http::header::CACHE_CONTROL,cache_control.parse().unwrap());
+				(axum::http::StatusCode::OK,headers,json).into_response()
+			}else{
+				axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+			}
+		},
+		Err(e)=>{
+			let mut headers=axum::http::HeaderMap::new();
+			headers.append("X-Proxy-Error",e.parse().unwrap());
+			(axum::http::StatusCode::BAD_GATEWAY,headers).into_response()
+		},
+	}
+}
+/** 取得したHTMLから要約を作る。Errは失敗した理由*/
+async fn summarize(v:&[u8],q:&RequestParams,sub:&subrequest::Subrequest<'_>,mut explain:explain::Explain)->Result<SummalyResult,String>{
+	let config=sub.config;
+	let content_length_limit=q.content_length_limit.unwrap_or(config.max_size);
+	let decode_start=std::time::Instant::now();
+	let decode_span=tracing::info_span!("decode").entered();
 	let mut meta_charset=None;
 	let mut content_type=None;
 	{
@@ -319,6 +938,7 @@ async fn remote_request(
 		}
 	}
 	let mut encoding=None;
+	let mut charset_source="default";
 	if let Some(content_type)=&content_type{
 		//content_type="text/html;charset=shift_jis"
 		for c in content_type.split(';'){
@@ -326,54 +946,48 @@ async fn remote_request(
 			if let Some(i)=c.find("charset="){
 				let charset=&c[i+"charset=".len()..];
 				encoding=encoding_rs::Encoding::for_label(charset.as_bytes());
+				charset_source="meta http-equiv";
 			}
 		}
 	}
 	if let Some(meta_charset)=&meta_charset{
 		if let Some(e)=encoding_rs::Encoding::for_label(meta_charset.as_bytes()){
 			encoding=Some(e);
+			charset_source="meta charset";
 		}
 	}
+	explain.charset(encoding.map(|e|e.name()).unwrap_or("UTF-8"),charset_source);
 	if encoding==Some(encoding_rs::UTF_8){
 		encoding=None;
 	}
 	let mut dst=Cow::Borrowed("");
 	if let Some(encoding)=encoding{
-		(dst,_,_)=encoding.decode(&v);
+		(dst,_,_)=encoding.decode(v);
 	}
 	let s=if dst.is_empty(){
-		String::from_utf8_lossy(&v)
+		String::from_utf8_lossy(v)
 	}else{
 		dst
 	};
+	tracing::debug!(encoding=encoding.map(|e|e.name()).unwrap_or("UTF-8"),"decoded");
+	drop(decode_span);
+	explain.timing("decode",decode_start.elapsed());
+	let parse_start=std::time::Instant::now();
+	let json_ld=tracing::info_span!("json_ld").in_scope(||jsonld::JsonLd::extract(&s));
 	let start=match s.find("<head").or_else(||s.find("<HEAD")){
 		Some(idx)=>idx,
-		None=>{
-			let mut headers=axum::http::HeaderMap::new();
-			headers.append("X-Proxy-Error","no head".parse().unwrap());
-			config.append_headers(&mut headers);
-			return (axum::http::StatusCode::BAD_GATEWAY,headers).into_response()
-		},
+		None=>return Err("no head".to_owned()),
 	};
 	let end=match s.find("</head>").or_else(||s.find("</HEAD>")){
 		Some(idx)=>idx,
-		None=>{
-			let mut headers=axum::http::HeaderMap::new();
-			headers.append("X-Proxy-Error","no /head".parse().unwrap());
-			config.append_headers(&mut headers);
-			return (axum::http::StatusCode::BAD_GATEWAY,headers).into_response()
-		},
+		None=>return Err("no /head".to_owned()),
 	};
 	let s=&s[start+6..end];
-	let dom=match html_parser::Dom::parse(s){
+	let dom=match tracing::info_span!("parse").in_scope(||html_parser::Dom::parse(s)){
 		Ok(idx)=>idx,
-		Err(e)=>{
-			let mut headers=axum::http::HeaderMap::new();
-			headers.append("X-Proxy-Error",e.to_string().parse().unwrap());
-			config.append_headers(&mut headers);
-			return (axum::http::StatusCode::BAD_GATEWAY,headers).into_response()
-		},
+		Err(e)=>return Err(e.to_string()),
 	};
+	explain.timing("parse",parse_start.elapsed());
 	let base_url=if let Ok(url)=reqwest::Url::parse(&q.url){
 		url
 	}else{
@@ -382,10 +996,24 @@ async fn remote_request(
 	let base_url_str=format!("{}://{}{}",base_url.scheme(),base_url.host_str().unwrap(),base_url.port().map(|n|format!(":{n}")).unwrap_or_default());
 	let mut player=SummalyPlayer{
 		url: None,
+		r#type: None,
 		width: None,
 		height: None,
 		allow: vec![],
 	};
+	let mut article=Article::default();
+	let mut meta_author=None;
+	let mut og_authors=vec![];
+	let mut icons=vec![];
+	//サムネイルの最後の候補にするapple-touch-icon(取得元,値,URL)
+	let mut touch_icons:Vec<(String,String,String)>=vec![];
+	let mut manifest_href=None;
+	let mut canonical_link=None;
+	let mut og_url=None;
+	let mut images:Vec<thumbnail::Image>=vec![];
+	let mut videos=vec![];
+	let mut audios=vec![];
+	let mut theme_color_media=false;
 	let mut resp=SummalyResult{
 		title: None,
 		icon: None,
@@ -397,11 +1025,22 @@ async fn remote_request(
 		activity_pub: None,
 		url: q.url.clone(),
 		oembed:None,
+		article:None,
+		theme_color:None,
+		canonical_url:None,
+		thumbnail_info:None,
+		images:vec![],
+		explain:None,
 	};
 	for node in dom.children.iter(){
 		if let html_parser::Node::Element(element)=node{
 			if element.name.as_str()=="title"{
-				if resp.title.is_none(){//og:title優先
+				let applied=resp.title.is_none();
+				if explain.enabled(){
+					let raw:String=element.children.iter().filter_map(|e|e.text()).collect();
+					explain.candidate("title","<title>",&raw,raw.trim(),applied&&!raw.trim().is_empty());
+				}
+				if applied{//og:title優先
 					let mut s=String::new();
 					for e in element.children.iter(){
 						if let Some(c)=e.text(){
@@ -420,112 +1059,197 @@ async fn remote_request(
 			}
 			match (element.name.as_str(),&element.attributes){
 				("meta",att)=>{
+					let raw=att.get("content").unwrap_or(&None).as_deref().unwrap_or_default();
 					match att.get("name").unwrap_or(&None).as_ref().map(|s|(
 						s.as_str(),
 						att.get("content").unwrap_or(&None).as_ref().map(|s|html_escape::decode_html_entities(s.trim())),
 					)){
 						Some(("msapplication-tooltip",Some(content))) => {
+							explain.candidate("description","meta[name=msapplication-tooltip]",raw,&content,resp.description.is_none());
 							if resp.description.is_none(){//og:description優先
 								resp.description=Some(content.into());
 							}
 						},
 						Some(("application-name",Some(content))) => {
+							explain.candidate("sitename","meta[name=application-name]",raw,&content,resp.sitename.is_none());
 							if resp.sitename.is_none(){//og:site_name優先
 								resp.sitename=Some(content.to_string());
 							}
+							explain.candidate("title","meta[name=application-name]",raw,&content,resp.title.is_none());
 							if resp.title.is_none(){//og:title優先
 								resp.title=Some(content.to_string());
 							}
 						},
+						Some(("author",Some(content))) if !content.is_empty() => {
+							explain.candidate("article.author","meta[name=author]",raw,&content,og_authors.is_empty());
+							meta_author=Some(content.into_owned());
+						},
+						Some(("theme-color",Some(content))) if !content.is_empty() => {
+							//prefers-color-scheme等の条件が無いものを優先
+							let has_media=att.get("media").map(|m|m.is_some()).unwrap_or(false);
+							let applied=resp.theme_color.is_none()||(theme_color_media&&!has_media);
+							explain.candidate("themeColor","meta[name=theme-color]",raw,&content,applied);
+							if applied{
+								resp.theme_color=Some(content.into());
+								theme_color_media=has_media;
+							}
+						},
+						Some(("keywords",Some(content))) => {
+							article.keywords=content.split(',').map(|k|k.trim()).filter(|k|!k.is_empty()).map(|k|k.to_owned()).collect();
+						},
 						_=>{}
 					}
 					match att.get("property").unwrap_or(&None).as_ref().map(|s|(
 						s.as_str(),
 						att.get("content").unwrap_or(&None).as_ref().map(|s|html_escape::decode_html_entities(s.trim())),
 					)){
-						Some(("og:image",Some(content))) => {
-							resp.thumbnail=Some(content.into());
+						Some(("og:image",Some(content))) if !content.is_empty() => {
+							images.push(thumbnail::Image{
+								url:content.into(),
+								..Default::default()
+							});
+						},
+						//以下は直前のog:imageについての情報
+						Some(("og:image:url",Some(content))) if !content.is_empty() => {
+							match images.last_mut(){
+								//og:image:secure_urlだけが先に書かれたか、og:imageと同じ値
+								Some(image) if image.url.is_empty()||image.url==*content=>{
+									image.url=content.into();
+								},
+								_=>images.push(thumbnail::Image{
+									url:content.into(),
+									..Default::default()
+								}),
+							}
+						},
+						Some(("og:image:secure_url",Some(content))) => {
+							match images.last_mut(){
+								Some(image) if image.secure_url.is_none()=>image.secure_url=Some(content.into()),
+								_=>images.push(thumbnail::Image{
+									secure_url:Some(content.into()),
+									..Default::default()
+								}),
+							}
+						},
+						Some(("og:image:alt",Some(content))) => {
+							if let Some(image)=images.last_mut(){
+								image.alt=Some(content.into());
+							}
+						},
+						Some(("og:image:width",Some(content))) => {
+							if let Some(image)=images.last_mut(){
+								image.width=content.parse().ok();
+							}
+						},
+						Some(("og:image:height",Some(content))) => {
+							if let Some(image)=images.last_mut(){
+								image.height=content.parse().ok();
+							}
+						},
+						Some(("og:image:type",Some(content))) => {
+							if let Some(image)=images.last_mut(){
+								image.r#type=Some(content.into());
+							}
 						},
 						Some(("og:url",Some(content))) => {
-							resp.url=content.into();
+							og_url=Some(content.into_owned());
 						},
 						Some(("og:title",Some(content))) => {
+							explain.candidate("title","meta[property=og:title]",raw,&content,true);
 							resp.title=Some(content.into());
 						},
 						Some(("og:description",Some(content))) => {
+							explain.candidate("description","meta[property=og:description]",raw,&content,true);
 							resp.description=Some(content.into());
 						},
 						Some(("description",Some(content))) => {
+							explain.candidate("description","meta[name=description]",raw,&content,true);
 							resp.description=Some(content.into());
 						},
 						Some(("og:site_name",Some(content))) => {
+							explain.candidate("sitename","meta[property=og:site_name]",raw,&content,true);
 							resp.sitename=Some(content.into());
 						},
-						Some(("og:video:url",Some(content))) => {
-							if player.url.is_none(){//og:video:secure_url優先
-								player.url=Some(content.into());
-							}
+						Some(("og:type",Some(content))) => {
+							explain.candidate("article.type","meta[property=og:type]",raw,&content,true);
+							article.r#type=Some(content.into());
 						},
-						Some(("og:video:secure_url",Some(content))) => {
-							player.url=Some(content.into());
+						Some(("article:published_time",Some(content))) => {
+							let time=normalize_time(&content);
+							explain.candidate("article.publishedTime","meta[property=article:published_time]",raw,time.as_deref().unwrap_or_default(),time.is_some());
+							if time.is_some(){
+								article.published_time=time;
+							}
 						},
-						Some(("og:video:width",Some(content))) => {
-							if let Ok(content)=content.parse::<f64>(){
-								player.width=Some(content);
+						Some(("article:modified_time",Some(content))) => {
+							let time=normalize_time(&content);
+							explain.candidate("article.modifiedTime","meta[property=article:modified_time]",raw,time.as_deref().unwrap_or_default(),time.is_some());
+							if time.is_some(){
+								article.modified_time=time;
 							}
 						},
-						Some(("og:video:height",Some(content))) => {
-							if let Ok(content)=content.parse::<f64>(){
-								player.height=Some(content);
+						Some(("article:author",Some(content))) => {
+							//プロフィールのURLは名前として表示できない
+							let applied=!content.is_empty()&&!content.starts_with("http");
+							explain.candidate("article.author","meta[property=article:author]",raw,&content,applied);
+							if applied{
+								og_authors.push(content.into_owned());
 							}
 						},
+						Some(("article:section",Some(content))) => {
+							explain.candidate("article.section","meta[property=article:section]",raw,&content,true);
+							article.section=Some(content.into());
+						},
+						Some(("article:tag",Some(content))) if !content.is_empty() => {
+							article.tags.push(content.into());
+						},
+						Some((property,Some(content))) if property.starts_with("og:video") => {
+							player::push_property(&mut videos,&property["og:video".len()..],&content);
+						},
+						Some((property,Some(content))) if property.starts_with("og:audio") => {
+							player::push_property(&mut audios,&property["og:audio".len()..],&content);
+						},
 						_ => {},
 					}
 				},
 				("link",att)=>{
+					let rel=att.get("rel").unwrap_or(&None).as_deref().unwrap_or_default();
+					let href=att.get("href").unwrap_or(&None).as_ref().map(|s|html_escape::decode_html_entities(s.trim()));
+					let raw=att.get("href").unwrap_or(&None).as_deref().unwrap_or_default();
+					if let Some(icon)=href.as_ref().and_then(|href|icon::IconCandidate::from_link(
+						rel,
+						href,
+						att.get("sizes").unwrap_or(&None).as_deref(),
+						att.get("type").unwrap_or(&None).as_deref(),
+					)){
+						if icon.is_apple_touch_icon(){
+							touch_icons.push((icon.source.clone(),raw.to_owned(),icon.href.clone()));
+						}
+						icons.push(icon);
+					}
+					if rel.split_ascii_whitespace().any(|t|t.eq_ignore_ascii_case("manifest")){
+						manifest_href=href.as_ref().map(|href|href.to_string());
+					}
+					if canonical_link.is_none()&&rel.split_ascii_whitespace().any(|t|t.eq_ignore_ascii_case("canonical")){
+						canonical_link=href.as_ref().map(|href|href.to_string());
+					}
 					match att.get("rel").unwrap_or(&None).as_ref().map(|s|(
 						s.as_str(),
 						att.get("href").unwrap_or(&None).as_ref().map(|s|html_escape::decode_html_entities(s.trim())),
 						att.get("type").unwrap_or(&None).as_ref().map(|t|t.as_str()),
 					)){
-						Some(("shortcut icon",Some(href),_)) => {
-							if resp.icon.is_none(){//icon優先
-								resp.icon=Some(href.into());
-							}
-						},
-						Some(("icon",Some(href),_)) => {
-							resp.icon=Some(href.into());
-						},
-						Some(("apple-touch-icon",Some(href),_)) => {
-							if resp.thumbnail.is_none(){//og:image優先
-								resp.thumbnail=Some(href.into());
-							}
-						},
 						Some(("alternate",Some(href),Some("application/json+oembed"))) => {
-							let embed_res=if let Ok(mut href)=urlencoding::decode(&href){
+							let oembed_start=std::time::Instant::now();
+							let embed_json=if let Ok(mut href)=urlencoding::decode(&href){
 								if let Some(s)=solve_url(&href,&base_url,&base_url_str,&None,""){
 									href=Cow::Owned(s);
 								}
-								let builder=client.get(href.as_ref());
-								let user_agent=q.user_agent.as_ref().unwrap_or_else(||&config.user_agent);
-								let builder=builder.header(reqwest::header::USER_AGENT,user_agent);
-								let timeout_ms=config.timeout.min(q.response_timeout.unwrap_or(u32::MAX) as u64);
-								let builder=builder.timeout(std::time::Duration::from_millis(timeout_ms));
-								builder.send().await.map_err(|e|{
-									println!("oembed {} {:?}",href,e);
-								}).ok()
-							}else{
-								None
-							};
-							let embed_json=if let Some(embed_res)=embed_res{
-								if let Ok(d)=load_all(embed_res,content_length_limit.into()).await{
-									serde_json::from_slice(&d).ok()
-								}else{
-									None
-								}
+								fetch_json(sub,&href,content_length_limit.into(),tracing::info_span!("oembed")).await
 							}else{
 								None
 							};
+							metrics::METRICS.oembed(embed_json.is_some());
+							explain.timing("oembed",oembed_start.elapsed());
 							if let Some(v)=embed_json{
 								resp.oembed=Some(v);
 							}
@@ -537,34 +1261,218 @@ async fn remote_request(
 			}
 		}
 	}
-	if let Some(v)=&resp.oembed{
-		if let Some(width)=v.width{
+	//httpsで取得できる最初の画像、無ければ最初の画像をサムネイルにする
+	let is_secure=|image:&thumbnail::Image|solve_url(image.preferred_url(),&base_url,&base_url_str,&None,"").map(|url|url.starts_with("https:")).unwrap_or(false);
+	let thumbnail_image=images.iter().position(is_secure).or(if images.is_empty(){None}else{Some(0)});
+	for (i,image) in images.iter().enumerate(){
+		let source=if image.secure_url.is_some(){
+			"meta[property=og:image:secure_url]"
+		}else{
+			"meta[property=og:image]"
+		};
+		explain.candidate("thumbnail",source,image.preferred_url(),image.preferred_url(),Some(i)==thumbnail_image);
+	}
+	let mut thumbnail_info=thumbnail::ImageInfo::default();
+	if let Some(image)=thumbnail_image.map(|i|&images[i]){
+		resp.thumbnail=Some(image.preferred_url().to_owned());
+		thumbnail_info=image.info();
+	}
+	if let Some(image)=json_ld.image{
+		explain.candidate("thumbnail","json-ld",&image,&image,resp.thumbnail.is_none());
+		if resp.thumbnail.is_none(){
+			resp.thumbnail=Some(image);
+		}
+	}
+	//og:imageもJSON-LDの画像も無い場合はアプリのアイコンを使う
+	for (source,raw,href) in touch_icons{
+		explain.candidate("thumbnail",&source,&raw,&href,resp.thumbnail.is_none());
+		if resp.thumbnail.is_none(){
+			resp.thumbnail=Some(href);
+		}
+	}
+	//headにサイト名か十分な大きさのアイコンが無い場合はmanifestで補う
+	let need_icon=icon::best(&icons,config.icon_size).map(|i|!icons[i].is_large_enough(config.icon_size)).unwrap_or(true);
+	let manifest_url=manifest_href.as_deref().and_then(|href|base_url.join(href).ok()).filter(|_|config.web_app_manifest&&(resp.sitename.is_none()||need_icon));
+	//manifestと/favicon.icoの確認、サムネイルの取得はページと同じ締め切りまで並行して行う
+	let icon_task=async{
+		let manifest_start=std::time::Instant::now();
+		let manifest:Option<manifest::Manifest>=match &manifest_url{
+			Some(url)=>fetch_json(sub,url.as_str(),content_length_limit.into(),tracing::info_span!("manifest")).await,
+			None=>None,
+		};
+		let manifest_elapsed=manifest_start.elapsed();
+		let manifest_icons=match (&manifest,&manifest_url){
+			(Some(manifest),Some(url))=>manifest.icons(url),
+			_=>vec![],
+		};
+		//headにもmanifestにもアイコンが無い場合のみ。確かめていない/favicon.icoは返さない
+		let favicon=match &config.favicon_probe{
+			Some(probe) if icons.is_empty()&&manifest_icons.is_empty()=>{
+				let probe_start=std::time::Instant::now();
+				let exists=favicon::probe(sub,&base_url_str,probe).instrument(tracing::info_span!("favicon")).await;
+				Some((exists,probe_start.elapsed()))
+			},
+			_=>None,
+		};
+		(manifest,manifest_elapsed,manifest_icons,favicon)
+	};
+	let thumbnail_task=async{
+		//media_proxyを通す前のURLを調べる
+		let url=resp.thumbnail.as_ref().and_then(|s|solve_url(s,&base_url,&base_url_str,&None,""));
+		let (Some(probe),Some(url))=(&config.thumbnail_probe,url) else{
+			return None;
+		};
+		let probe_start=std::time::Instant::now();
+		thumbnail::probe(sub,&url,probe,&mut thumbnail_info).instrument(tracing::info_span!("thumbnail")).await;
+		Some(probe_start.elapsed())
+	};
+	let ((manifest,manifest_elapsed,manifest_icons,favicon),thumbnail_elapsed)=futures::future::join(icon_task,thumbnail_task).await;
+	if manifest_url.is_some(){
+		explain.timing("manifest",manifest_elapsed);
+	}
+	if let Some(manifest)=manifest{
+		if let Some(name)=manifest.name(){
+			explain.candidate("sitename","manifest",name,name,resp.sitename.is_none());
+			if resp.sitename.is_none(){
+				resp.sitename=Some(name.to_owned());
+			}
+		}
+		if let Some(color)=manifest.theme_color.as_deref().map(|s|s.trim()).filter(|s|!s.is_empty()){
+			explain.candidate("themeColor","manifest",color,color,resp.theme_color.is_none());
+			if resp.theme_color.is_none(){
+				resp.theme_color=Some(color.to_owned());
+			}
+		}
+	}
+	icons.extend(manifest_icons);
+	if let Some(elapsed)=thumbnail_elapsed{
+		explain.timing("thumbnail",elapsed);
+	}
+	let best_icon=icon::best(&icons,config.icon_size);
+	for (i,icon) in icons.iter().enumerate(){
+		explain.candidate("icon",&icon.source,&icon.href,&icon.href,Some(i)==best_icon);
+	}
+	if let Some(i)=best_icon{
+		resp.icon=Some(icons.swap_remove(i).href);
+	}
+	//JSON-LDはmetaタグが無い場合に使う
+	if let Some(title)=json_ld.title{
+		explain.candidate("title","json-ld",&title,&title,resp.title.is_none());
+		if resp.title.is_none(){
+			resp.title=Some(title);
+		}
+	}
+	if let Some(description)=json_ld.description{
+		explain.candidate("description","json-ld",&description,&description,resp.description.is_none());
+		if resp.description.is_none(){
+			resp.description=Some(description);
+		}
+	}
+	if let Some(sitename)=json_ld.sitename{
+		explain.candidate("sitename","json-ld",&sitename,&sitename,resp.sitename.is_none());
+		if resp.sitename.is_none(){
+			resp.sitename=Some(sitename);
+		}
+	}
+	//article:author、meta[name=author]、JSON-LDの順
+	if !og_authors.is_empty(){
+		article.author=Some(og_authors.join(", "));
+	}else if meta_author.is_some(){
+		article.author=meta_author;
+	}
+	if let Some(ld)=json_ld.article{
+		article.headline=ld.headline;
+		for (field,value,ld_value) in [
+			("article.author",&mut article.author,ld.author),
+			("article.publishedTime",&mut article.published_time,ld.published_time),
+			("article.modifiedTime",&mut article.modified_time,ld.modified_time),
+			("article.section",&mut article.section,ld.section),
+		]{
+			if let Some(ld_value)=ld_value{
+				explain.candidate(field,"json-ld",&ld_value,&ld_value,value.is_none());
+				if value.is_none(){
+					*value=Some(ld_value);
+				}
+			}
+		}
+		if article.tags.is_empty(){
+			article.tags=ld.tags;
+		}
+		if article.keywords.is_empty(){
+			article.keywords=ld.keywords;
+		}
+	}
+	if !article.is_empty(){
+		resp.article=Some(article);
+	}
+	//使えるURLを持つ最初の候補を選ぶ
+	for (tag,player_type) in player::candidates(&videos,&audios){
+		let source=match (player_type,tag.secure_url.is_some()){
+			(player::PlayerType::Audio,true)=>"meta[property=og:audio:secure_url]",
+			(player::PlayerType::Audio,false)=>"meta[property=og:audio]",
+			(_,true)=>"meta[property=og:video:secure_url]",
+			(_,false)=>"meta[property=og:video]",
+		};
+		let raw=tag.preferred_url().unwrap_or_default();
+		match config.player.check_url(raw,player_type,&base_url){
+			Ok(url)=>{
+				explain.candidate("player.url",source,raw,&url,true);
+				player.url=Some(url);
+			},
+			Err(reason)=>{
+				tracing::debug!(url=config.log.url(raw),reason=reason,"player rejected");
+				explain.candidate("player.url",source,raw,raw,false);
+				explain.reject("player.url",&reason);
+				continue;
+			}
+		}
+		explain.candidate("player.type",source,tag.mime.as_deref().unwrap_or_default(),player_type.as_str(),true);
+		player.r#type=Some(player_type);
+		if let Some(width)=tag.width{
+			explain.candidate("player.width",source,&width.to_string(),&width.to_string(),true);
 			player.width=Some(width);
 		}
-		if let Some(height)=v.height{
+		if let Some(height)=tag.height{
+			explain.candidate("player.height",source,&height.to_string(),&height.to_string(),true);
 			player.height=Some(height);
 		}
-		const SAFE_LIST:[&'static str;6] = [
-			"autoplay",
-			"clipboard-write",
-			"fullscreen",
-			"encrypted-media",
-			"picture-in-picture",
-			"web-share",
-		];
-		if let Some(html)=v.html.as_ref().map(|v|v.as_str()){
-			if let Ok(html)=html_parser::Dom::parse(html){
-				for node in html.children.iter(){
-					if let html_parser::Node::Element(node)=node{
-						if let Some(Some(allow))=node.attributes.get("allow"){
-							for allow in allow.split(";"){
-								let allow=allow.trim();
-								if SAFE_LIST.contains(&allow){
-									player.allow.push(allow.to_owned());
-								}
-							}
+		break;
+	}
+	//oEmbedのiframeもog:video等と同じ確認をして、使えなければhtmlを返さない
+	if let Some(v)=&mut resp.oembed{
+		if let Some(html)=&v.html{
+			let iframe=player::OEmbedIframe::parse(html);
+			let raw=iframe.as_ref().map(|iframe|iframe.src.as_str()).unwrap_or_default();
+			let checked=match &iframe{
+				Some(iframe)=>config.player.check_url(&iframe.src,player::PlayerType::Iframe,&base_url),
+				None=>Err("no iframe in oembed html".to_owned()),
+			};
+			match checked{
+				Ok(url)=>{
+					//og:video等のプレイヤーがあればoEmbedの寸法やallowは混ぜない
+					let applied=player.url.is_none();
+					explain.candidate("player.url","oembed",raw,&url,applied);
+					if applied{
+						player.url=Some(url);
+						player.r#type=Some(player::PlayerType::Iframe);
+						if let Some(width)=v.width{
+							explain.candidate("player.width","oembed",&width.to_string(),&width.to_string(),true);
+							player.width=Some(width);
+						}
+						if let Some(height)=v.height{
+							explain.candidate("player.height","oembed",&height.to_string(),&height.to_string(),true);
+							player.height=Some(height);
+						}
+						if let Some(allow)=iframe.as_ref().and_then(|iframe|iframe.allow.as_deref()){
+							player.allow=config.player.allowed_features(allow);
 						}
 					}
+				},
+				Err(reason)=>{
+					tracing::debug!(url=config.log.url(raw),reason=reason,"oembed player rejected");
+					explain.candidate("player.url","oembed",raw,raw,false);
+					explain.reject("player.url",&reason);
+					v.html=None;
 				}
 			}
 		}
@@ -575,31 +1483,85 @@ async fn remote_request(
 			resp.player=player;
 		}
 	}
-	if resp.icon.is_none(){
-		resp.icon=Some(format!("{}/favicon.ico",base_url_str));
+	if let Some((exists,elapsed))=favicon{
+		let favicon=format!("{}/favicon.ico",base_url_str);
+		explain.timing("favicon",elapsed);
+		explain.candidate("icon","fallback","/favicon.ico",&favicon,exists);
+		if exists&&resp.icon.is_none(){
+			resp.icon=Some(favicon);
+		}
 	}
 	if let Some(Some(icon))=resp.icon.as_ref().map(|s|
 		solve_url(s,&base_url,&base_url_str,&config.media_proxy,"icon.webp")
 	){
 		resp.icon=Some(icon);
 	}
+	if resp.thumbnail.is_some()&&!thumbnail_info.is_empty(){
+		resp.thumbnail_info=Some(thumbnail_info);
+	}
 	if let Some(Some(thumbnail))=resp.thumbnail.as_ref().map(|s|
 		solve_url(s,&base_url,&base_url_str,&config.media_proxy,"thumbnail.webp")
 	){
 		resp.thumbnail=Some(thumbnail);
 	}
+	resp.images=images.into_iter().filter_map(|mut image|{
+		image.url=solve_url(image.preferred_url(),&base_url,&base_url_str,&config.media_proxy,"image.webp")?;
+		Some(image)
+	}).collect();
+	//rel=canonical、og:urlの順に最初に使えるもの
+	for (source,raw) in [("link[rel=canonical]",canonical_link),("meta[property=og:url]",og_url)]{
+		let Some(raw)=raw else{
+			continue;
+		};
+		let url=match canonical::resolve(&raw,&base_url){
+			Ok(url)=>url,
+			Err(reason)=>{
+				explain.candidate("canonicalUrl",source,&raw,&raw,false);
+				explain.reject("canonicalUrl",&reason);
+				continue;
+			}
+		};
+		match config.canonical_url{
+			canonical::CanonicalPolicy::SameSite=>{
+				if !canonical::same_site(&url,&base_url){
+					//他のサイトを装ったプレビューを防ぐ
+					tracing::debug!(url=config.log.url(url.as_str()),"canonical url on another site");
+					explain.candidate("canonicalUrl",source,&raw,url.as_str(),false);
+					explain.reject("canonicalUrl","not on the same site as the fetched url");
+					continue;
+				}
+				explain.candidate("url",source,&raw,url.as_str(),true);
+				resp.url=url.to_string();
+			},
+			canonical::CanonicalPolicy::Separate=>{},
+		}
+		explain.candidate("canonicalUrl",source,&raw,url.as_str(),true);
+		resp.canonical_url=Some(url.into());
+		break;
+	}
 	if let Some(url)=solve_url(&resp.url,&base_url,&base_url_str,&None,""){
 		resp.url=url;
 	}
-	if let Ok(json)=serde_json::to_string(&resp){
-		let mut headers=axum::http::HeaderMap::new();
-		headers.append(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
-		headers.append(axum::http::header::CACHE_CONTROL,"public, max-age=1800".parse().unwrap());
-		config.append_headers(&mut headers);
-		(axum::http::StatusCode::OK,headers,json).into_response()
-	}else{
-		axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+	if explain.enabled(){
+		explain.resolve("icon",|s|solve_url(s,&base_url,&base_url_str,&config.media_proxy,"icon.webp"));
+		explain.resolve("thumbnail",|s|solve_url(s,&base_url,&base_url_str,&config.media_proxy,"thumbnail.webp"));
+		explain.resolve("url",|s|solve_url(s,&base_url,&base_url_str,&None,""));
+		explain.timing("total",decode_start.elapsed());
 	}
+	resp.explain=explain.finish();
+	Ok(resp)
+}
+/** oEmbedやmanifestのような付随するJSONを要約と同じサイズの制限とページと共有する締め切りで読み込む*/
+async fn fetch_json<T:serde::de::DeserializeOwned>(sub:&subrequest::Subrequest<'_>,url:&str,content_length_limit:u64,span:tracing::Span)->Option<T>{
+	async{
+		let (res,_tracker)=sub.send(sub.client.get(url),url,sub.deadline(None)).await.map_err(|e|{
+			tracing::warn!(url=sub.config.log.url(url),error=%e,"fetch failed");
+		}).ok()?;
+		let d=load_all(res,content_length_limit).await.ok()?;
+		serde_json::from_slice(&d).map_err(|e|{
+			tracing::debug!(url=sub.config.log.url(url),error=%e,"invalid json");
+		}).ok()
+	}.instrument(span).await
 }
 async fn load_all(resp: reqwest::Response,content_length_limit:u64)->Result<Vec<u8>,String>{
 	let len_hint=resp.content_length().unwrap_or(content_length_limit);
@@ -614,8 +1576,12 @@ async fn load_all(resp: reqwest::Response,content_length_limit:u64)->Result<Vec<
 				if response_bytes.len()+b.len()>content_length_limit as usize{
 					return Err(format!("length:{}>{}",response_bytes.len()+b.len(),content_length_limit))
 				}
+				metrics::METRICS.downloaded(b.len());
 				response_bytes.extend_from_slice(&b);
 			},
+			Err(e) if e.is_timeout()=>{
+				return Err(format!("Timeout:{:?}",e))
+			},
 			Err(e)=>{
 				return Err(format!("LoadAll:{:?}",e))
 			}
@@ -662,3 +1628,133 @@ fn solve_url(icon:&str,base_url:&reqwest::Url,base_url_str:&str,media_proxy:&Opt
 		Some(icon.into_owned())
 	}
 }
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn rate_limit()->RateLimit{
+		RateLimit{
+			hosts:Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+			circuits:Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+			backoffs:Arc::new(tokio::sync::RwLock::new(HashMap::new())),
+			clients:Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+		}
+	}
+	fn runtime()->tokio::runtime::Runtime{
+		tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
+	}
+	fn rate_limit_config(rate:f64,burst:u32,trusted_proxies:&[&str])->ClientRateLimitConfig{
+		ClientRateLimitConfig{
+			rate,
+			burst,
+			trusted_proxies:trusted_proxies.iter().map(|s|s.parse().unwrap()).collect(),
+		}
+	}
+	fn forwarded_for(values:&[&str])->axum::http::HeaderMap{
+		let mut headers=axum::http::HeaderMap::new();
+		for v in values{
+			headers.append("X-Forwarded-For",v.parse().unwrap());
+		}
+		headers
+	}
+	#[test]
+	fn client_ip_untrusted_peer(){
+		let config=rate_limit_config(1.0,1,&["10.0.0.0/8"]);
+		//信用しない接続元のX-Forwarded-Forは無視する
+		let ip=config.client_ip("192.0.2.1".parse().unwrap(),&forwarded_for(&["198.51.100.1"]));
+		assert_eq!(ip.to_string(),"192.0.2.1");
+	}
+	#[test]
+	fn client_ip_trusted_hops(){
+		let config=rate_limit_config(1.0,1,&["10.0.0.0/8"]);
+		let ip=config.client_ip("10.0.0.1".parse().unwrap(),&forwarded_for(&["198.51.100.1, 10.0.0.2"]));
+		assert_eq!(ip.to_string(),"198.51.100.1");
+		//複数のヘッダに分かれていても順番に辿る
+		let ip=config.client_ip("10.0.0.1".parse().unwrap(),&forwarded_for(&["198.51.100.1","10.0.0.2"]));
+		assert_eq!(ip.to_string(),"198.51.100.1");
+		//ヘッダが無ければ接続元
+		let ip=config.client_ip("10.0.0.1".parse().unwrap(),&axum::http::HeaderMap::new());
+		assert_eq!(ip.to_string(),"10.0.0.1");
+	}
+	#[test]
+	fn client_ip_spoofed_leftmost(){
+		let config=rate_limit_config(1.0,1,&["10.0.0.0/8"]);
+		//クライアントが付けた左端の値ではなく、信用するプロキシが見た最初の接続元を使う
+		let ip=config.client_ip("10.0.0.1".parse().unwrap(),&forwarded_for(&["203.0.113.9, 198.51.100.1"]));
+		assert_eq!(ip.to_string(),"198.51.100.1");
+		let ip=config.client_ip("10.0.0.1".parse().unwrap(),&forwarded_for(&["10.0.0.3, 198.51.100.1"]));
+		assert_eq!(ip.to_string(),"198.51.100.1");
+		//解釈できない値より先は信用しない
+		let ip=config.client_ip("10.0.0.1".parse().unwrap(),&forwarded_for(&["203.0.113.9, unknown, 10.0.0.2"]));
+		assert_eq!(ip.to_string(),"10.0.0.2");
+	}
+	#[test]
+	fn client_ipv6_prefix(){
+		let config=rate_limit_config(1.0,1,&[]);
+		let key=config.client_key("2001:db8:1:2:3:4:5:6".parse().unwrap(),&axum::http::HeaderMap::new());
+		assert_eq!(key,"2001:db8:1:2::/64");
+		let key=config.client_key("::ffff:192.0.2.1".parse().unwrap(),&axum::http::HeaderMap::new());
+		assert_eq!(key,"192.0.2.1");
+	}
+	#[test]
+	fn client_buckets_use_their_own_limits(){
+		let small=rate_limit_config(0.0,1,&[]);
+		let large=rate_limit_config(0.0,100,&[]);
+		runtime().block_on(async{
+			let limit=rate_limit();
+			for i in 0..4096{
+				limit.client(format!("key:large{}",i),&large).await.unwrap();
+			}
+			//小さい制限のキーで整理しても、回復中の大きい制限のキーは満タン扱いで消されない
+			limit.client("key:small".to_owned(),&small).await.unwrap();
+			let lock=limit.clients.lock().await;
+			let bucket=lock.get("key:large4095").unwrap();
+			assert_eq!(bucket.tokens(std::time::Instant::now()),99.0);
+		});
+	}
+	#[test]
+	fn backoff_doubles_across_expiry(){
+		let config=ConfigFile{
+			backoff_base:20,
+			backoff_max:10000,
+			..Default::default()
+		};
+		runtime().block_on(async{
+			let limit=rate_limit();
+			let host="example.com".to_owned();
+			for i in 0..4u32{
+				let tracker=RateLimitTracker(Some(limit.clone()),Some(host.clone()),tokio::runtime::Handle::current());
+				let before=std::time::Instant::now();
+				tracker.report(true,Some(UpstreamRetryAfter(None)),&config).await;
+				let backoff=limit.backoffs.read().await.get(&host).copied().unwrap();
+				let expected=std::time::Duration::from_millis(20<<i);
+				assert_eq!(backoff.count,i+1);
+				assert!(backoff.until>=before+expected,"{}: {:?}",i,backoff.until-before);
+				assert!(backoff.until<=std::time::Instant::now()+expected,"{}: {:?}",i,backoff.until-before);
+				//期限が過ぎるまで待ってから再び429を受け取る
+				tokio::time::sleep_until((backoff.until+std::time::Duration::from_millis(5)).into()).await;
+			}
+			//成功すれば最初からやり直す
+			let tracker=RateLimitTracker(Some(limit.clone()),Some(host.clone()),tokio::runtime::Handle::current());
+			tracker.report(true,None,&config).await;
+			assert!(limit.backoffs.read().await.get(&host).is_none());
+		});
+	}
+	#[test]
+	fn backoff_failure_keeps_count(){
+		let config=ConfigFile{
+			backoff_base:10,
+			..Default::default()
+		};
+		runtime().block_on(async{
+			let limit=rate_limit();
+			let host="example.com".to_owned();
+			let tracker=RateLimitTracker(Some(limit.clone()),Some(host.clone()),tokio::runtime::Handle::current());
+			tracker.report(true,Some(UpstreamRetryAfter(None)),&config).await;
+			tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+			//期限後の接続失敗では回数を忘れない
+			tracker.report(false,None,&config).await;
+			assert_eq!(limit.backoffs.read().await.get(&host).map(|b|b.count),Some(1));
+		});
+	}
+}
@@ -0,0 +1,32 @@
+use serde::Deserialize;
+
+use crate::icon::IconCandidate;
+
+/** Web App Manifestのうち要約に使う項目*/
+#[derive(Debug,Deserialize)]
+pub struct Manifest{
+	name:Option<String>,
+	short_name:Option<String>,
+	#[serde(default)]
+	icons:Vec<ManifestIcon>,
+	pub theme_color:Option<String>,
+}
+#[derive(Debug,Deserialize)]
+struct ManifestIcon{
+	src:String,
+	sizes:Option<String>,
+	r#type:Option<String>,
+	purpose:Option<String>,
+}
+impl Manifest{
+	pub fn name(&self)->Option<&str>{
+		self.name.as_deref().or(self.short_name.as_deref()).map(|s|s.trim()).filter(|s|!s.is_empty())
+	}
+	/** srcはmanifestのURLを基準に解決する*/
+	pub fn icons(&self,manifest_url:&reqwest::Url)->Vec<IconCandidate>{
+		self.icons.iter().filter_map(|icon|{
+			let src=manifest_url.join(icon.src.trim()).ok()?;
+			IconCandidate::from_manifest(src.as_str(),icon.sizes.as_deref(),icon.r#type.as_deref(),icon.purpose.as_deref())
+		}).collect()
+	}
+}
@@ -0,0 +1,156 @@
+use std::{collections::BTreeMap, fmt::Write, sync::{atomic::{AtomicU64, Ordering}, LazyLock, Mutex}};
+
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::RateLimit;
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig{
+	/** 別のポートで公開する場合のアドレス。nullの場合はbind_addrで公開する*/
+	#[serde(default)]
+	pub bind_addr:Option<String>,
+	#[serde(default="default_path")]
+	pub path:String,
+}
+fn default_path()->String{
+	"/metrics".to_owned()
+}
+/** 上流からの取得時間のバケット(秒)*/
+const FETCH_BUCKETS:[f64;9]=[0.05,0.1,0.25,0.5,1.0,2.5,5.0,10.0,30.0];
+pub struct Metrics{
+	/** (outcome,status)毎のリクエスト数*/
+	requests:Mutex<BTreeMap<(&'static str,u16),u64>>,
+	fetch_buckets:[AtomicU64;FETCH_BUCKETS.len()],
+	fetch_count:AtomicU64,
+	fetch_sum_micros:AtomicU64,
+	downloaded_bytes:AtomicU64,
+	rate_limited:AtomicU64,
+	oembed_success:AtomicU64,
+	oembed_failure:AtomicU64,
+	favicon_cache_hit:AtomicU64,
+	favicon_cache_miss:AtomicU64,
+}
+pub static METRICS:LazyLock<Metrics>=LazyLock::new(||Metrics{
+	requests:Mutex::new(BTreeMap::new()),
+	fetch_buckets:Default::default(),
+	fetch_count:AtomicU64::new(0),
+	fetch_sum_micros:AtomicU64::new(0),
+	downloaded_bytes:AtomicU64::new(0),
+	rate_limited:AtomicU64::new(0),
+	oembed_success:AtomicU64::new(0),
+	oembed_failure:AtomicU64::new(0),
+	favicon_cache_hit:AtomicU64::new(0),
+	favicon_cache_miss:AtomicU64::new(0),
+});
+impl Metrics{
+	pub fn request(&self,status:axum::http::StatusCode){
+		let outcome=if status.is_success(){
+			"success"
+		}else if status==axum::http::StatusCode::TOO_MANY_REQUESTS{
+			"rate_limited"
+		}else if status.is_client_error(){
+			"client_error"
+		}else{
+			"upstream_error"
+		};
+		if status==axum::http::StatusCode::TOO_MANY_REQUESTS{
+			self.rate_limited.fetch_add(1,Ordering::Relaxed);
+		}
+		*self.requests.lock().unwrap().entry((outcome,status.as_u16())).or_insert(0)+=1;
+	}
+	/** 上流からページを取得し終わるまでの時間*/
+	pub fn fetch(&self,elapsed:std::time::Duration){
+		let secs=elapsed.as_secs_f64();
+		for (bucket,le) in self.fetch_buckets.iter().zip(FETCH_BUCKETS.iter()){
+			if secs<=*le{
+				bucket.fetch_add(1,Ordering::Relaxed);
+			}
+		}
+		self.fetch_count.fetch_add(1,Ordering::Relaxed);
+		self.fetch_sum_micros.fetch_add(elapsed.as_micros() as u64,Ordering::Relaxed);
+	}
+	pub fn downloaded(&self,bytes:usize){
+		self.downloaded_bytes.fetch_add(bytes as u64,Ordering::Relaxed);
+	}
+	pub fn oembed(&self,success:bool){
+		if success{
+			self.oembed_success.fetch_add(1,Ordering::Relaxed);
+		}else{
+			self.oembed_failure.fetch_add(1,Ordering::Relaxed);
+		}
+	}
+	pub fn favicon_cache(&self,hit:bool){
+		if hit{
+			self.favicon_cache_hit.fetch_add(1,Ordering::Relaxed);
+		}else{
+			self.favicon_cache_miss.fetch_add(1,Ordering::Relaxed);
+		}
+	}
+	/** 一度も引いていない場合は0*/
+	fn favicon_cache_hit_ratio(&self)->f64{
+		let hit=self.favicon_cache_hit.load(Ordering::Relaxed);
+		let total=hit+self.favicon_cache_miss.load(Ordering::Relaxed);
+		if total==0{
+			0.0
+		}else{
+			hit as f64/total as f64
+		}
+	}
+	/** Prometheusのテキスト形式で書き出す*/
+	async fn render(&self,limit:&RateLimit)->String{
+		let mut out=String::new();
+		out+="# HELP summaly_requests_total Summary requests by outcome and status code.\n";
+		out+="# TYPE summaly_requests_total counter\n";
+		for ((outcome,status),count) in self.requests.lock().unwrap().iter(){
+			writeln!(out,"summaly_requests_total{{outcome=\"{}\",status=\"{}\"}} {}",outcome,status,count).unwrap();
+		}
+		out+="# HELP summaly_rate_limited_total Requests rejected with 429.\n";
+		out+="# TYPE summaly_rate_limited_total counter\n";
+		writeln!(out,"summaly_rate_limited_total {}",self.rate_limited.load(Ordering::Relaxed)).unwrap();
+		out+="# HELP summaly_upstream_fetch_duration_seconds Time to fetch the upstream page.\n";
+		out+="# TYPE summaly_upstream_fetch_duration_seconds histogram\n";
+		for (bucket,le) in self.fetch_buckets.iter().zip(FETCH_BUCKETS.iter()){
+			writeln!(out,"summaly_upstream_fetch_duration_seconds_bucket{{le=\"{}\"}} {}",le,bucket.load(Ordering::Relaxed)).unwrap();
+		}
+		let count=self.fetch_count.load(Ordering::Relaxed);
+		writeln!(out,"summaly_upstream_fetch_duration_seconds_bucket{{le=\"+Inf\"}} {}",count).unwrap();
+		writeln!(out,"summaly_upstream_fetch_duration_seconds_sum {}",self.fetch_sum_micros.load(Ordering::Relaxed) as f64/1_000_000.0).unwrap();
+		writeln!(out,"summaly_upstream_fetch_duration_seconds_count {}",count).unwrap();
+		out+="# HELP summaly_downloaded_bytes_total Bytes downloaded from upstream servers.\n";
+		out+="# TYPE summaly_downloaded_bytes_total counter\n";
+		writeln!(out,"summaly_downloaded_bytes_total {}",self.downloaded_bytes.load(Ordering::Relaxed)).unwrap();
+		out+="# HELP summaly_oembed_fetch_total oEmbed fetches by result.\n";
+		out+="# TYPE summaly_oembed_fetch_total counter\n";
+		writeln!(out,"summaly_oembed_fetch_total{{result=\"success\"}} {}",self.oembed_success.load(Ordering::Relaxed)).unwrap();
+		writeln!(out,"summaly_oembed_fetch_total{{result=\"failure\"}} {}",self.oembed_failure.load(Ordering::Relaxed)).unwrap();
+		out+="# HELP summaly_favicon_cache_total Favicon probe cache lookups by result.\n";
+		out+="# TYPE summaly_favicon_cache_total counter\n";
+		writeln!(out,"summaly_favicon_cache_total{{result=\"hit\"}} {}",self.favicon_cache_hit.load(Ordering::Relaxed)).unwrap();
+		writeln!(out,"summaly_favicon_cache_total{{result=\"miss\"}} {}",self.favicon_cache_miss.load(Ordering::Relaxed)).unwrap();
+		out+="# HELP summaly_favicon_cache_hit_ratio Ratio of favicon probe cache hits to lookups since startup.\n";
+		out+="# TYPE summaly_favicon_cache_hit_ratio gauge\n";
+		writeln!(out,"summaly_favicon_cache_hit_ratio {}",self.favicon_cache_hit_ratio()).unwrap();
+		let hosts=limit.hosts.read().await;
+		out+="# HELP summaly_ratelimit_hosts Hosts tracked by the per-host rate limit.\n";
+		out+="# TYPE summaly_ratelimit_hosts gauge\n";
+		writeln!(out,"summaly_ratelimit_hosts {}",hosts.len()).unwrap();
+		out+="# HELP summaly_ratelimit_active_slots Active per-host rate limit slots.\n";
+		out+="# TYPE summaly_ratelimit_active_slots gauge\n";
+		writeln!(out,"summaly_ratelimit_active_slots {}",hosts.values().map(|v|*v as u64).sum::<u64>()).unwrap();
+		out
+	}
+}
+pub async fn get_metrics(limit:RateLimit)->axum::response::Response{
+	let body=METRICS.render(&limit).await;
+	let mut headers=axum::http::HeaderMap::new();
+	headers.append(axum::http::header::CONTENT_TYPE,"text/plain; version=0.0.4".parse().unwrap());
+	(axum::http::StatusCode::OK,headers,body).into_response()
+}
+/** 要約のリクエストの結果を数える*/
+pub async fn record_request(req:axum::extract::Request,next:axum::middleware::Next)->axum::response::Response{
+	let res=next.run(req).await;
+	METRICS.request(res.status());
+	res
+}
@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlayerConfig{
+	/** iframeでの埋め込みを許すホスト。サブドメインも含む。空の場合は全て許す*/
+	#[serde(default)]
+	pub allowed_hosts:Vec<String>,
+	/** oEmbedのiframeのallow属性から残す機能*/
+	#[serde(default="default_allow")]
+	pub allow:Vec<String>,
+}
+fn default_allow()->Vec<String>{
+	[
+		"autoplay",
+		"clipboard-write",
+		"fullscreen",
+		"encrypted-media",
+		"picture-in-picture",
+		"web-share",
+	].into_iter().map(|s|s.to_owned()).collect()
+}
+impl Default for PlayerConfig{
+	fn default()->Self{
+		Self{
+			allowed_hosts:vec![],
+			allow:default_allow(),
+		}
+	}
+}
+impl PlayerConfig{
+	pub fn validate(&self)->Vec<String>{
+		let mut errors=vec![];
+		for (i,host) in self.allowed_hosts.iter().enumerate(){
+			if host.is_empty()||host.contains(['/',':','*']){
+				errors.push(format!("player.allowed_hosts[{}]: expected a host name, got {:?}",i,host));
+			}
+		}
+		errors
+	}
+	/** ページを基準に解決したURLを返す。使えない場合はその理由*/
+	pub fn check_url(&self,url:&str,player_type:PlayerType,base_url:&reqwest::Url)->Result<String,String>{
+		let url=base_url.join(url.trim()).map_err(|e|format!("invalid url: {}",e))?;
+		if url.scheme()!="https"{
+			return Err(format!("scheme {} is not allowed",url.scheme()));
+		}
+		let host=url.host_str().ok_or_else(||"no host".to_owned())?.to_ascii_lowercase();
+		//動画や音声のファイルはiframeと違ってページを埋め込まない
+		if player_type==PlayerType::Iframe&&!self.allowed_hosts.is_empty(){
+			let allowed=self.allowed_hosts.iter().any(|h|{
+				let h=h.to_ascii_lowercase();
+				host==h||host.ends_with(&format!(".{}",h))
+			});
+			if !allowed{
+				return Err(format!("host {} is not in player.allowed_hosts",host));
+			}
+		}
+		Ok(url.into())
+	}
+	/** iframeのallow属性のうち許可された機能*/
+	pub fn allowed_features(&self,allow:&str)->Vec<String>{
+		allow.split(';').map(|a|a.trim()).filter(|a|self.allow.iter().any(|f|f==a)).map(|a|a.to_owned()).collect()
+	}
+}
+/** oEmbedのhtmlに含まれるiframe*/
+#[derive(Clone,Debug,PartialEq)]
+pub struct OEmbedIframe{
+	pub src:String,
+	pub allow:Option<String>,
+}
+impl OEmbedIframe{
+	/** 最初のiframe要素。srcが無い場合もNone*/
+	pub fn parse(html:&str)->Option<Self>{
+		fn find(nodes:&[html_parser::Node])->Option<&html_parser::Element>{
+			nodes.iter().find_map(|node|match node{
+				html_parser::Node::Element(e) if e.name.eq_ignore_ascii_case("iframe")=>Some(e),
+				html_parser::Node::Element(e)=>find(&e.children),
+				_=>None,
+			})
+		}
+		let dom=html_parser::Dom::parse(html).ok()?;
+		let iframe=find(&dom.children)?;
+		let attribute=|name:&str|iframe.attributes.get(name).cloned().flatten().map(|v|html_escape::decode_html_entities(&v).into_owned());
+		Some(Self{
+			src:attribute("src")?,
+			allow:attribute("allow"),
+		})
+	}
+}
+/** クライアントがプレイヤーをどう埋め込むか*/
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum PlayerType{
+	/** iframeで埋め込むページ*/
+	Iframe,
+	/** <video>で再生するファイル*/
+	Video,
+	/** <audio>で再生するファイル*/
+	Audio,
+}
+impl PlayerType{
+	pub fn as_str(&self)->&'static str{
+		match self{
+			Self::Iframe=>"iframe",
+			Self::Video=>"video",
+			Self::Audio=>"audio",
+		}
+	}
+}
+/** og:video、og:audioとそれに続く:secure_url等の組*/
+#[derive(Clone,Debug,Default)]
+pub struct MediaTag{
+	pub url:Option<String>,
+	pub secure_url:Option<String>,
+	pub mime:Option<String>,
+	pub width:Option<f64>,
+	pub height:Option<f64>,
+}
+impl MediaTag{
+	/** secure_urlがあればそちらを使う*/
+	pub fn preferred_url(&self)->Option<&str>{
+		self.secure_url.as_deref().or(self.url.as_deref())
+	}
+	fn video_type(&self)->Option<PlayerType>{
+		self.preferred_url()?;
+		let mime=self.mime.as_deref().map(|s|s.to_ascii_lowercase());
+		match mime.as_deref(){
+			Some("text/html")=>Some(PlayerType::Iframe),
+			Some(m) if m.starts_with("video/")||m=="application/x-mpegurl"||m=="application/vnd.apple.mpegurl"=>Some(PlayerType::Video),
+			Some(m) if m.starts_with("audio/")=>Some(PlayerType::Audio),
+			//application/x-shockwave-flash等は再生できない
+			Some(_)=>None,
+			None=>Some(guess_type(self.preferred_url()?,PlayerType::Iframe)),
+		}
+	}
+	fn audio_type(&self)->Option<PlayerType>{
+		self.preferred_url()?;
+		let mime=self.mime.as_deref().map(|s|s.to_ascii_lowercase());
+		match mime.as_deref(){
+			Some("text/html")=>Some(PlayerType::Iframe),
+			Some(m) if m.starts_with("audio/")=>Some(PlayerType::Audio),
+			Some(_)=>None,
+			None=>Some(guess_type(self.preferred_url()?,PlayerType::Audio)),
+		}
+	}
+}
+/** 種類が書かれていない場合は拡張子で判断する*/
+fn guess_type(url:&str,default:PlayerType)->PlayerType{
+	let path=url.split(['?','#']).next().unwrap_or_default();
+	let ext=path.rsplit_once('.').map(|(_,ext)|ext.to_ascii_lowercase()).unwrap_or_default();
+	match ext.as_str(){
+		"mp4"|"m4v"|"webm"|"mov"|"ogv"|"m3u8"=>PlayerType::Video,
+		"mp3"|"m4a"|"aac"|"oga"|"ogg"|"opus"|"wav"|"flac"=>PlayerType::Audio,
+		_=>default,
+	}
+}
+/** iframeで埋め込めるog:video、動画、音声の順に並べる*/
+pub fn candidates<'a>(videos:&'a [MediaTag],audios:&'a [MediaTag])->Vec<(&'a MediaTag,PlayerType)>{
+	let videos=videos.iter().filter_map(|v|Some((v,v.video_type()?)));
+	let audios=audios.iter().filter_map(|a|Some((a,a.audio_type()?)));
+	let mut candidates:Vec<_>=videos.chain(audios).collect();
+	candidates.sort_by_key(|(_,t)|*t as u8);
+	candidates
+}
+/** og:video:secure_url等のog:video以降を受け取って直前のタグに加える*/
+pub fn push_property(tags:&mut Vec<MediaTag>,suffix:&str,content:&str){
+	match suffix{
+		"" if !content.is_empty()=>tags.push(MediaTag{
+			url:Some(content.to_owned()),
+			..Default::default()
+		}),
+		//:secure_urlだけが先に書かれたか、og:videoと同じ値
+		":url" if !content.is_empty()=>match tags.last_mut(){
+			Some(tag) if tag.url.as_deref().map(|url|url==content).unwrap_or(true)=>tag.url=Some(content.to_owned()),
+			_=>tags.push(MediaTag{
+				url:Some(content.to_owned()),
+				..Default::default()
+			}),
+		},
+		":secure_url"=>match tags.last_mut(){
+			Some(tag) if tag.secure_url.is_none()=>tag.secure_url=Some(content.to_owned()),
+			_=>tags.push(MediaTag{
+				secure_url:Some(content.to_owned()),
+				..Default::default()
+			}),
+		},
+		":type"=>if let Some(tag)=tags.last_mut(){
+			tag.mime=Some(content.to_owned());
+		},
+		":width"=>if let Some(tag)=tags.last_mut(){
+			tag.width=content.parse().ok();
+		},
+		":height"=>if let Some(tag)=tags.last_mut(){
+			tag.height=content.parse().ok();
+		},
+		_=>{}
+	}
+}
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	#[test]
+	fn oembed_iframe(){
+		let iframe=OEmbedIframe::parse(r#"<div><iframe src="https://www.youtube.com/embed/x?a=1&amp;b=2" allow="autoplay; camera; fullscreen"></iframe></div>"#).unwrap();
+		assert_eq!(iframe.src,"https://www.youtube.com/embed/x?a=1&b=2");
+		let config=PlayerConfig::default();
+		assert_eq!(config.allowed_features(iframe.allow.as_deref().unwrap()),vec!["autoplay","fullscreen"]);
+		assert!(OEmbedIframe::parse("<blockquote>text</blockquote><script src=\"https://example.com/a.js\"></script>").is_none());
+		let base=reqwest::Url::parse("https://example.com/page").unwrap();
+		let iframe=OEmbedIframe::parse(r#"<iframe src="javascript:alert(1)"></iframe>"#).unwrap();
+		assert!(config.check_url(&iframe.src,PlayerType::Iframe,&base).is_err());
+	}
+}
@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use crate::{ConfigFile, RateLimit, RateLimitError, RateLimitTracker, RequestParams, UpstreamRetryAfter};
+
+/** 要約に付随する取得(oEmbed、manifest、favicon、サムネイル)。ページの取得と締め切りと流量制限を共有する*/
+#[derive(Clone,Copy)]
+pub struct Subrequest<'a>{
+	pub client:&'a reqwest::Client,
+	/** CLIやreadyzの自己診断ではnull*/
+	limit:Option<&'a RateLimit>,
+	/** ページの取得で枠を持っているホスト。同じホストへの取得はその枠で行う*/
+	page_host:Option<&'a str>,
+	pub config:&'a ConfigFile,
+	pub user_agent:&'a str,
+	/** ページの取得を始めた時刻にtimeoutを足したもの*/
+	deadline:tokio::time::Instant,
+}
+impl<'a> Subrequest<'a>{
+	pub fn new(client:&'a reqwest::Client,limit:Option<&'a RateLimit>,page_host:Option<&'a str>,config:&'a ConfigFile,q:&'a RequestParams,deadline:tokio::time::Instant)->Self{
+		Self{
+			client,
+			limit,
+			page_host,
+			config,
+			user_agent:q.user_agent.as_deref().unwrap_or(&config.user_agent),
+			deadline,
+		}
+	}
+	/** 共有する締め切りと個別のtimeoutの早い方*/
+	pub fn deadline(&self,timeout:Option<Duration>)->tokio::time::Instant{
+		match timeout{
+			Some(timeout)=>self.deadline.min(tokio::time::Instant::now()+timeout),
+			None=>self.deadline,
+		}
+	}
+	/** ホスト毎の制限とサーキットブレーカーを確認してdeadlineまでに送る。ページと同じホストはページの枠を共有する。返したRateLimitTrackerは本文を読み終わってから捨てる*/
+	pub async fn send(&self,builder:reqwest::RequestBuilder,url:&str,deadline:tokio::time::Instant)->Result<(reqwest::Response,Option<RateLimitTracker>),String>{
+		let limit=match self.limit{
+			Some(limit)=>Some((limit,crate::host_key(url).ok_or("invalid url")?)),
+			None=>None,
+		};
+		let tracker=match &limit{
+			//ページと同じホストに更に枠を取ると自身の枠が空くのを待つ事になる
+			Some((limit,host)) if self.page_host==Some(host.as_str())=>{
+				limit.check(host,self.config).await.map_err(reason)?;
+				None
+			},
+			Some((limit,_))=>Some(self.acquire(limit,url,deadline).await?),
+			None=>None,
+		};
+		let remaining=deadline.saturating_duration_since(tokio::time::Instant::now());
+		if remaining.is_zero(){
+			return Err("deadline exceeded".to_owned());
+		}
+		let res=builder.header(reqwest::header::USER_AGENT,self.user_agent).timeout(remaining).send().await;
+		if let Some((limit,host))=&limit{
+			match &res{
+				Ok(res) if res.status()==reqwest::StatusCode::TOO_MANY_REQUESTS||res.status()==reqwest::StatusCode::SERVICE_UNAVAILABLE=>{
+					let retry_after=res.headers().get(reqwest::header::RETRY_AFTER).and_then(|v|v.to_str().ok()).and_then(crate::parse_retry_after);
+					limit.report(host,true,Some(UpstreamRetryAfter(retry_after)),self.config).await;
+				},
+				Ok(_)=>limit.report(host,true,None,self.config).await,
+				//締め切りや個別のtimeoutは上流の障害とみなさない
+				Err(e) if e.is_timeout()=>{},
+				Err(_)=>limit.report(host,false,None,self.config).await,
+			}
+		}
+		let res=res.map_err(|e|e.to_string())?;
+		Ok((res,tracker))
+	}
+	/** 枠が空くまでdeadlineの前まで待つ。サーキットが開いているかバックオフ中のホストは諦める*/
+	async fn acquire(&self,limit:&RateLimit,url:&str,deadline:tokio::time::Instant)->Result<RateLimitTracker,String>{
+		const RETRY_INTERVAL:Duration=Duration::from_millis(100);
+		loop{
+			match limit.request(url,self.config).await{
+				Ok(tracker)=>return Ok(tracker),
+				Err(RateLimitError::Retry) if tokio::time::Instant::now()+RETRY_INTERVAL<deadline=>{
+					tokio::time::sleep(RETRY_INTERVAL).await;
+				},
+				Err(e)=>return Err(reason(e)),
+			}
+		}
+	}
+}
+fn reason(e:RateLimitError)->String{
+	match e{
+		RateLimitError::Retry=>"rate limited".to_owned(),
+		RateLimitError::InvalidUrl=>"invalid url".to_owned(),
+		RateLimitError::CircuitOpen(state,_)=>format!("circuit {}",state),
+		RateLimitError::Backoff(_)=>"upstream backoff".to_owned(),
+	}
+}
@@ -0,0 +1,278 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::subrequest::Subrequest;
+
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum Placeholder{
+	None,
+	Blurhash,
+	Color,
+}
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThumbnailProbeConfig{
+	/** 寸法と形式を調べるために読む先頭のバイト数*/
+	#[serde(default="default_header_size")]
+	pub header_size:u64,
+	/** 読み込みと変換を諦めるまでの時間(ms)*/
+	#[serde(default="default_timeout")]
+	pub timeout:u64,
+	/** 読み込みが終わる前に表示する代わりの値*/
+	#[serde(default="default_placeholder")]
+	pub placeholder:Placeholder,
+	/** placeholderのために読み込む画像の最大サイズ(bytes)*/
+	#[serde(default="default_max_size")]
+	pub max_size:u64,
+}
+fn default_header_size()->u64{
+	32*1024
+}
+fn default_timeout()->u64{
+	2000
+}
+fn default_placeholder()->Placeholder{
+	Placeholder::None
+}
+fn default_max_size()->u64{
+	2*1024*1024
+}
+impl Default for ThumbnailProbeConfig{
+	fn default()->Self{
+		Self{
+			header_size:default_header_size(),
+			timeout:default_timeout(),
+			placeholder:default_placeholder(),
+			max_size:default_max_size(),
+		}
+	}
+}
+/** 画像の読み込み前にレイアウトを決めるための情報*/
+#[derive(Clone,Debug,Default,Serialize,Deserialize)]
+pub struct ImageInfo{
+	pub width:Option<u32>,
+	pub height:Option<u32>,
+	pub r#type:Option<String>,
+	#[serde(skip_serializing_if="Option::is_none")]
+	pub blurhash:Option<String>,
+	/** "#rrggbb"*/
+	#[serde(skip_serializing_if="Option::is_none")]
+	pub color:Option<String>,
+}
+impl ImageInfo{
+	pub fn is_empty(&self)->bool{
+		self.width.is_none()&&self.height.is_none()&&self.r#type.is_none()&&self.blurhash.is_none()&&self.color.is_none()
+	}
+}
+/** og:imageとそれに続くog:image:*の組*/
+#[derive(Clone,Debug,Default,Serialize,Deserialize)]
+pub struct Image{
+	/** og:image:secure_urlだけの場合は空*/
+	pub url:String,
+	#[serde(skip)]
+	pub secure_url:Option<String>,
+	pub alt:Option<String>,
+	pub width:Option<u32>,
+	pub height:Option<u32>,
+	pub r#type:Option<String>,
+}
+impl Image{
+	/** secure_urlがあればそちらを使う*/
+	pub fn preferred_url(&self)->&str{
+		self.secure_url.as_deref().unwrap_or(&self.url)
+	}
+	pub fn info(&self)->ImageInfo{
+		ImageInfo{
+			width:self.width,
+			height:self.height,
+			r#type:self.r#type.clone(),
+			..Default::default()
+		}
+	}
+}
+/** 寸法が分からない場合は先頭を、placeholderが必要な場合は全体を読み込んで補う*/
+pub async fn probe(sub:&Subrequest<'_>,url:&str,config:&ThumbnailProbeConfig,info:&mut ImageInfo){
+	let need_header=info.width.is_none()||info.height.is_none();
+	let need_decode=config.placeholder!=Placeholder::None;
+	if !need_header&&!need_decode{
+		//形式だけのために取得はしない
+		if info.r#type.is_none(){
+			info.r#type=guess_type(url).map(|s|s.to_owned());
+		}
+		return;
+	}
+	let limit=if need_decode{
+		config.max_size
+	}else{
+		config.header_size
+	};
+	let timeout=Duration::from_millis(config.timeout);
+	let deadline=sub.deadline(Some(timeout));
+	let probe=async{
+		let mut builder=sub.client.get(url);
+		if !need_decode{
+			builder=builder.header(reqwest::header::RANGE,format!("bytes=0-{}",limit.saturating_sub(1)));
+		}
+		let (res,_tracker)=sub.send(builder,url,deadline).await?;
+		if !res.status().is_success(){
+			return Err(format!("status {}",res.status()));
+		}
+		let content_type=res.headers().get(reqwest::header::CONTENT_TYPE).and_then(|v|v.to_str().ok()).map(|s|s.to_owned());
+		let bytes=load_prefix(res,limit,need_decode).await?;
+		Ok((content_type,bytes))
+	};
+	let (content_type,bytes)=match tokio::time::timeout_at(deadline,probe).await{
+		Ok(Ok(v))=>v,
+		Ok(Err(e))=>{
+			tracing::debug!(error=e,"thumbnail probe");
+			return;
+		},
+		Err(_)=>{
+			tracing::debug!("thumbnail probe timed out");
+			return;
+		}
+	};
+	let placeholder=config.placeholder;
+	let decoded=tokio::task::spawn_blocking(move||decode(&bytes,placeholder));
+	let decoded=match tokio::time::timeout_at(sub.deadline(Some(timeout)),decoded).await{
+		Ok(Ok(decoded))=>decoded,
+		_=>return,
+	};
+	let (format,dimensions,placeholder)=decoded;
+	if info.r#type.is_none(){
+		info.r#type=format.map(|f|f.to_mime_type().to_owned()).or(content_type.filter(|s|s.starts_with("image/")));
+	}
+	if let Some((width,height))=dimensions{
+		if info.width.is_none()||info.height.is_none(){
+			info.width=Some(width);
+			info.height=Some(height);
+		}
+	}
+	match placeholder{
+		Some((Placeholder::Blurhash,v))=>info.blurhash=Some(v),
+		Some((Placeholder::Color,v))=>info.color=Some(v),
+		_=>{}
+	}
+}
+/** 拡張子から形式を推測する*/
+fn guess_type(url:&str)->Option<&'static str>{
+	let path=url.split(['?','#']).next().unwrap_or_default();
+	let ext=path.rsplit_once('.').map(|(_,ext)|ext.to_ascii_lowercase())?;
+	image::ImageFormat::from_extension(ext).map(|f|f.to_mime_type())
+}
+/** limitまで読む。allの場合はlimitを超えたら失敗とする*/
+async fn load_prefix(res:reqwest::Response,limit:u64,all:bool)->Result<Vec<u8>,String>{
+	if all&&res.content_length().unwrap_or(0)>limit{
+		return Err(format!("length:{}>{}",res.content_length().unwrap_or(0),limit));
+	}
+	let mut bytes=vec![];
+	let mut stream=res.bytes_stream();
+	while let Some(b)=stream.next().await{
+		let b=b.map_err(|e|e.to_string())?;
+		crate::metrics::METRICS.downloaded(b.len());
+		bytes.extend_from_slice(&b);
+		if bytes.len() as u64>=limit{
+			if all{
+				return Err(format!("length:{}>{}",bytes.len(),limit));
+			}
+			bytes.truncate(limit as usize);
+			break;
+		}
+	}
+	Ok(bytes)
+}
+/** 形式、寸法、placeholderの値*/
+type Decoded=(Option<image::ImageFormat>,Option<(u32,u32)>,Option<(Placeholder,String)>);
+fn decode(bytes:&[u8],placeholder:Placeholder)->Decoded{
+	let reader=||{
+		let mut reader=image::ImageReader::new(std::io::Cursor::new(bytes)).with_guessed_format().ok()?;
+		let mut limits=image::Limits::default();
+		limits.max_image_width=Some(16384);
+		limits.max_image_height=Some(16384);
+		limits.max_alloc=Some(128*1024*1024);
+		reader.limits(limits);
+		Some(reader)
+	};
+	let format=reader().and_then(|r|r.format());
+	let dimensions=reader().and_then(|r|r.into_dimensions().ok());
+	if placeholder==Placeholder::None{
+		return (format,dimensions,None);
+	}
+	let image=match reader().and_then(|r|r.decode().ok()){
+		Some(image)=>image,
+		None=>return (format,dimensions,None),
+	};
+	let small=image.thumbnail(32,32);
+	let value=match placeholder{
+		Placeholder::Blurhash=>{
+			let rgba=small.to_rgba8();
+			match blurhash::encode(4,3,rgba.width(),rgba.height(),rgba.as_raw()){
+				Ok(v)=>v,
+				Err(_)=>return (format,dimensions,None),
+			}
+		},
+		_=>average_color(&small.to_rgb8()),
+	};
+	(format,dimensions,Some((placeholder,value)))
+}
+fn average_color(image:&image::RgbImage)->String{
+	let mut sum=[0u64;3];
+	for p in image.pixels(){
+		for (s,v) in sum.iter_mut().zip(p.0){
+			*s+=v as u64;
+		}
+	}
+	let n=(image.width() as u64*image.height() as u64).max(1);
+	format!("#{:02x}{:02x}{:02x}",sum[0]/n,sum[1]/n,sum[2]/n)
+}
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn encoded(format:image::ImageFormat)->Vec<u8>{
+		let image=image::RgbImage::from_fn(40,30,|x,y|image::Rgb([(x*6) as u8,(y*8) as u8,((x*y)%256) as u8]));
+		let mut bytes=std::io::Cursor::new(vec![]);
+		image.write_to(&mut bytes,format).unwrap();
+		bytes.into_inner()
+	}
+	#[test]
+	fn truncated_headers(){
+		for format in [image::ImageFormat::Png,image::ImageFormat::Jpeg,image::ImageFormat::Gif]{
+			let bytes=encoded(format);
+			//header_sizeで画像データの途中まで読んだ場合。JPEGはSOSまでのセグメントが揃っている必要がある
+			let end=match format{
+				image::ImageFormat::Jpeg=>bytes.windows(2).position(|w|w==[0xff,0xda]).unwrap()+32,
+				_=>bytes.len()/2,
+			};
+			assert!(end<bytes.len());
+			let (guessed,dimensions,_)=decode(&bytes[..end],Placeholder::None);
+			assert_eq!(guessed,Some(format));
+			assert_eq!(dimensions,Some((40,30)),"{:?}",format);
+		}
+		//寸法が届いていない
+		let bytes=encoded(image::ImageFormat::Png);
+		assert_eq!(decode(&bytes[..16],Placeholder::None),(Some(image::ImageFormat::Png),None,None));
+	}
+	#[test]
+	fn placeholders(){
+		let image=image::RgbImage::from_pixel(64,48,image::Rgb([255,0,0]));
+		let mut bytes=std::io::Cursor::new(vec![]);
+		image.write_to(&mut bytes,image::ImageFormat::Png).unwrap();
+		let bytes=bytes.into_inner();
+		let (_,_,placeholder)=decode(&bytes,Placeholder::Color);
+		assert_eq!(placeholder,Some((Placeholder::Color,"#ff0000".to_owned())));
+		let Some((Placeholder::Blurhash,hash))=decode(&bytes,Placeholder::Blurhash).2 else{
+			panic!("no blurhash");
+		};
+		//4x3成分
+		assert_eq!(hash.len(),28);
+		assert!(hash.starts_with('L'));
+		let pixels=blurhash::decode(&hash,4,4,1.0).unwrap();
+		for p in pixels.chunks(4){
+			assert!(p[0]>=240&&p[1]<=16&&p[2]<=16,"{:?}",p);
+		}
+	}
+}